@@ -0,0 +1,60 @@
+/*++ @file
+
+    Copyright ©2024-2024 Liu Yi, efikarl@yeah.net
+
+    This program is just made available under the terms and conditions of the
+    MIT license: http://www.efikarl.com/mit-license.html
+
+    THE PROGRAM IS DISTRIBUTED UNDER THE MIT LICENSE ON AN "AS IS" BASIS,
+    WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
+--*/
+
+use network::tftp::packet::*;
+use std::hint::black_box;
+
+const ROUNDS: u32 = 1_000_000;
+
+// time f over ROUNDS runs and print the time of one of them
+fn bench<F: FnMut()>(name: &str, mut f: F) {
+    for _ in 0..ROUNDS / 100 {
+        f();
+    }
+    let time = std::time::Instant::now();
+    for _ in 0..ROUNDS {
+        f();
+    }
+    println!("{:<24} {:>8.1} ns", name, time.elapsed().as_nanos() as f64 / ROUNDS as f64);
+}
+
+fn main() {
+    for blksize in [TFTP_SIZE_DATA_BLOCK, 1428, TFTP_SIZE_BLOCK_MAX] {
+        println!("blksize = {}", blksize);
+        let data = (0..blksize).map(|i| i as u8).collect::<Vec<u8>>();
+        let raw = Packet::newdat(1, data.clone()).encode();
+        bench("decode Packet", || {
+            black_box(Packet::try_decode(black_box(&raw)).unwrap());
+        });
+        bench("decode PacketRef", || {
+            black_box(PacketRef::try_decode(black_box(&raw)).unwrap());
+        });
+        bench("encode Packet", || {
+            black_box(Packet::newdat(1, black_box(&data).clone()).encode());
+        });
+        let mut buf = vec![0u8;blksize + 4];
+        bench("encode_into PacketRef", || {
+            black_box(PacketRef::Dat(1, black_box(&data)).encode_into(&mut buf));
+        });
+    }
+    let raw = Packet::newrrq("pxelinux.cfg/default", "octet").with_options(vec![
+        (String::from("blksize"), String::from("1428")),
+        (String::from("tsize"), String::from("0")),
+        (String::from("windowsize"), String::from("16")),
+    ]).encode();
+    println!("rrq");
+    bench("decode Packet", || {
+        black_box(Packet::try_decode(black_box(&raw)).unwrap());
+    });
+    bench("decode PacketRef", || {
+        black_box(PacketRef::try_decode(black_box(&raw)).unwrap());
+    });
+}
@@ -10,28 +10,267 @@
 --*/
 
 use network::prelude::*;
+use network::tftp::packet::TFTP_PORT;
+use network::tftp::netascii::Mode;
 
-fn main() {
-    let client = Client::new("127.0.0.1:0");
+const USAGE: &str = "\
+usage: tftp_client [options] get|put HOST[:PORT] REMOTE [LOCAL]
 
-    let mut text = String::new();
-    text.push_str(&"1".repeat(512));
-    text.push('\n');
-    text.push_str(&"2".repeat(512));
-    text.push('\n');
+get REMOTE from HOST into LOCAL, or put LOCAL to HOST as REMOTE. LOCAL is the
+file name of REMOTE by default, - for stdout or stdin.
 
-    let target = "target/client/tftp.0.log".try_create_parent(true).unwrap();
-    let mut file = std::fs::OpenOptions::new().create(true).read(true).append(true).open(&target).unwrap();
-    file.write(text.as_bytes()).unwrap();
+options:
+  -m, --mode MODE          octet (default) or netascii
+  -b, --blksize SIZE       block size to negotiate
+  -w, --windowsize SIZE    window size to negotiate
+  -t, --timeout SECS       seconds to wait before a retransmission, negotiated too
+  -r, --retries COUNT      retransmissions before giving up
+  -T, --tsize              negotiate the transfer size
+  -v, --verbose            trace packets on stdout
+  -q, --quiet              no progress bar
+  -h, --help               print this help
 
-    client.send("target/client/tftp.0.log", "target/server/tftp.x.log");
-    // std::thread::sleep(std::time::Duration::from_secs(1));
-    client.recv("target/server/tftp.x.log", "target/client/tftp.1.log");
+exit codes:
+  0        done
+  1        local i/o error
+  2        usage error
+  3        timeout
+  4        protocol error
+  10+code  error packet of the server, e.g. 11 file not found, 12 access violation";
 
-    let send = std::fs::read("target/client/tftp.0.log").unwrap();
-    let text = std::fs::read("target/server/tftp.x.log").unwrap();
-    let recv = std::fs::read("target/client/tftp.1.log").unwrap();
+const EXIT_IO      : u8 =  1;
+const EXIT_USAGE   : u8 =  2;
+const EXIT_TIMEOUT : u8 =  3;
+const EXIT_PROTOCOL: u8 =  4;
+const EXIT_REMOTE  : u8 = 10;
 
-    assert_eq!(send, text);
-    assert_eq!(text, recv);
+#[derive(Default)]
+struct Args {
+    put       : bool,
+    host      : (String, u16),
+    remote    : String,
+    local     : String,
+    mode      : Mode,
+    blksize   : Option<u16>,
+    windowsize: Option<u16>,
+    timeout   : Option<u64>,
+    retries   : Option<u32>,
+    tsize     : bool,
+    verbose   : bool,
+    quiet     : bool,
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("{}: invalid value {}", flag, value))
+}
+
+fn args<I: Iterator<Item = String>>(mut argv: I) -> Result<Option<Args>, String> {
+    let mut args = Args::default();
+    let mut free = vec![];
+    while let Some(arg) = argv.next() {
+        // --flag=value as well as --flag value
+        let (flag, mut value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg, None),
+        };
+        let mut value = || value.take().or_else(|| argv.next());
+        match flag.as_str() {
+            "-m" | "--mode" => {
+                let mode = value().ok_or("--mode needs a value")?;
+                args.mode = Mode::from_name(&mode).ok_or(format!("--mode: unsupported mode {}", mode))?;
+            },
+            "-b" | "--blksize"    => args.blksize = Some(parse(&flag, value())?),
+            "-w" | "--windowsize" => args.windowsize = Some(parse(&flag, value())?),
+            "-t" | "--timeout"    => args.timeout = Some(parse(&flag, value())?),
+            "-r" | "--retries"    => args.retries = Some(parse(&flag, value())?),
+            "-T" | "--tsize"      => args.tsize = true,
+            "-v" | "--verbose"    => args.verbose = true,
+            "-q" | "--quiet"      => args.quiet = true,
+            "-h" | "--help"       => return Ok(None),
+            "-" => free.push(flag),
+            _ if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+            _ => free.push(flag),
+        }
+    }
+    let mut free = free.into_iter();
+    args.put = match free.next().as_deref() {
+        Some("get") => false,
+        Some("put") => true,
+        Some(cmd) => return Err(format!("unknown command {}", cmd)),
+        None => return Err(String::from("get or put expected")),
+    };
+    args.host = host(&free.next().ok_or("HOST expected")?)?;
+    args.remote = free.next().ok_or("REMOTE expected")?;
+    // the file name of REMOTE by default, which may be separated by backslashes for a dos-like server
+    args.local = match free.next() {
+        Some(local) => local,
+        None => match args.remote.rsplit(['/', '\\']).next() {
+            Some(name) if !["", ".", ".."].contains(&name) => name.to_string(),
+            _ => return Err(String::from("LOCAL expected")),
+        },
+    };
+    if let Some(arg) = free.next() {
+        return Err(format!("unexpected argument {}", arg));
+    }
+    if args.verbose && !args.put && args.local == "-" {
+        return Err(String::from("--verbose would trace into the file on stdout"));
+    }
+    Ok(Some(args))
+}
+
+// HOST, HOST:PORT, [IPV6] or [IPV6]:PORT, a bare ipv6 address taking the default port
+fn host(host: &str) -> Result<(String, u16), String> {
+    let invalid = || format!("invalid host {}", host);
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']').ok_or_else(invalid)? {
+            (name, "") => (name, None),
+            (name, port) => (name, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+        },
+        None => match host.rsplit_once(':') {
+            Some((name, port)) if !name.contains(':') => (name, Some(port)),
+            _ => (host, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid())?,
+        None => TFTP_PORT,
+    };
+    Ok((name.to_string(), port))
+}
+
+fn human(len: u64) -> String {
+    match len {
+        0..=1023 => format!("{} B", len),
+        1024..=1048575 => format!("{:.1} KiB", len as f64 / 1024.0),
+        _ => format!("{:.1} MiB", len as f64 / 1048576.0),
+    }
+}
+
+// a progress bar on stderr, redrawn ten times a second at most
+fn progress(len: u64, size: Option<u64>, time: std::time::Instant, last: &std::sync::Mutex<Option<std::time::Instant>>) {
+    let mut last = last.lock().unwrap();
+    if last.is_some_and(|last| last.elapsed() < std::time::Duration::from_millis(100)) && Some(len) != size {
+        return;
+    }
+    *last = Some(std::time::Instant::now());
+    let rate = human((len as f64 / time.elapsed().as_secs_f64().max(0.001)) as u64);
+    match size {
+        Some(size) if size > 0 => {
+            let done = (len.min(size) * 30 / size) as usize;
+            eprint!("\r[{}{}] {:>3}% {} / {} {}/s   ", "#".repeat(done), "-".repeat(30 - done), len.min(size) * 100 / size, human(len), human(size), rate);
+        },
+        _ => eprint!("\r{} {}/s   ", human(len), rate),
+    }
+}
+
+fn exit(e: &TftpError) -> u8 {
+    match e {
+        TftpError::Io(_)          => EXIT_IO,
+        TftpError::Timeout        => EXIT_TIMEOUT,
+        TftpError::Protocol(_)    => EXIT_PROTOCOL,
+        TftpError::Remote(..)     => EXIT_REMOTE + e.code().map(u16::from).unwrap_or_default() as u8,
+    }
+}
+
+fn run(args: Args) -> Result<TransferStats, TftpError> {
+    let mut client = Client::new(args.host)?;
+    client.mode(args.mode).info(args.verbose);
+    if let Some(blksize) = args.blksize {
+        client.option("blksize", blksize);
+    }
+    if let Some(windowsize) = args.windowsize {
+        client.option("windowsize", windowsize);
+    }
+    if let Some(timeout) = args.timeout {
+        client.timeout(timeout).option("timeout", timeout);
+    }
+    if let Some(retries) = args.retries {
+        client.retry(retries, 1);
+    }
+    if args.tsize {
+        client.option("tsize", 0);
+    }
+    if !args.quiet && !args.verbose && std::io::IsTerminal::is_terminal(&std::io::stderr()) {
+        let time = std::time::Instant::now();
+        let last = std::sync::Mutex::new(None);
+        client.progress(move |len, size| progress(len, size, time, &last));
+    }
+    match (args.put, args.local.as_str()) {
+        (false, "-") => client.get_to_writer(&args.remote, &mut std::io::stdout().lock()),
+        (false, _)   => client.recv(&args.remote, &args.local),
+        (true, "-")  => client.put_from_reader(&args.remote, std::io::stdin().lock()),
+        (true, _)    => client.send(&args.local, &args.remote),
+    }
+}
+
+fn main() -> std::process::ExitCode {
+    let args = match args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            writeln!(std::io::stdout(), "{}", USAGE).unwrap_or_default();
+            return std::process::ExitCode::SUCCESS;
+        },
+        Err(e) => {
+            eprintln!("tftp_client: {}\n{}\ntry --help for more", e, USAGE.lines().next().unwrap_or_default());
+            return std::process::ExitCode::from(EXIT_USAGE);
+        },
+    };
+    let quiet = args.quiet || args.verbose || !std::io::IsTerminal::is_terminal(&std::io::stderr());
+    match run(args) {
+        Ok(stats) => {
+            if !quiet {
+                eprintln!("\n{} in {:.1}s", human(stats.bytes), stats.elapsed.as_secs_f64());
+            }
+            std::process::ExitCode::SUCCESS
+        },
+        Err(e) => {
+            if !quiet {
+                eprintln!();
+            }
+            eprintln!("tftp_client: {}", e);
+            std::process::ExitCode::from(exit(&e))
+        },
+    }
+}
+
+#[test]
+fn test_host() {
+    assert_eq!(host("boot"), Ok((String::from("boot"), TFTP_PORT)));
+    assert_eq!(host("10.0.0.1:6969"), Ok((String::from("10.0.0.1"), 6969)));
+    assert_eq!(host("[fe80::1]:6969"), Ok((String::from("fe80::1"), 6969)));
+    assert_eq!(host("[fe80::1]"), Ok((String::from("fe80::1"), TFTP_PORT)));
+    // a bare ipv6 address, its last group not taken as a port
+    assert_eq!(host("fe80::1"), Ok((String::from("fe80::1"), TFTP_PORT)));
+    assert_eq!(host("::1"), Ok((String::from("::1"), TFTP_PORT)));
+    assert!(host("boot:tftp").is_err());
+    assert!(host("boot:65536").is_err());
+    assert!(host("[fe80::1").is_err());
+    assert!(host("[fe80::1]6969").is_err());
+}
+
+#[test]
+fn test_args() {
+    let argv = |argv: &str| args(argv.split_whitespace().map(String::from));
+    let args = argv("get boot dir/pxelinux.0").unwrap().unwrap();
+    assert!(!args.put);
+    assert_eq!(args.host, (String::from("boot"), TFTP_PORT));
+    assert_eq!((args.remote.as_str(), args.local.as_str()), ("dir/pxelinux.0", "pxelinux.0"));
+    assert_eq!(argv("get boot dir\\boot.ini").unwrap().unwrap().local, "boot.ini");
+    // --flag=value as well as --flag value, before or after the command
+    let args = argv("--blksize=1428 put -w 8 [::1]:6969 up/cfg cfg --mode netascii -T").unwrap().unwrap();
+    assert!(args.put);
+    assert_eq!(args.host, (String::from("::1"), 6969));
+    assert_eq!((args.remote.as_str(), args.local.as_str()), ("up/cfg", "cfg"));
+    assert_eq!((args.blksize, args.windowsize, args.mode, args.tsize), (Some(1428), Some(8), Mode::Netascii, true));
+    // - for stdout or stdin
+    let args = argv("get -q boot cfg -").unwrap().unwrap();
+    assert_eq!((args.local.as_str(), args.quiet), ("-", true));
+    assert_eq!(argv("put boot cfg -").unwrap().unwrap().local, "-");
+    assert!(argv("get -v boot cfg -").is_err());
+    assert!(argv("-h get").unwrap().is_none());
+    // usage errors
+    for bad in ["", "boot cfg", "get", "get boot", "get boot dir/", "get boot ..", "get boot cfg cfg extra", "get -x boot cfg", "get -b boot cfg", "get --blksize=big boot cfg", "get -m mail boot cfg"] {
+        assert!(argv(bad).is_err(), "{}", bad);
+    }
 }
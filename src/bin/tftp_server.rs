@@ -13,5 +13,5 @@ use network::prelude::*;
 
 fn main() {
     let server = Server::new();
-    server.listen();
+    server.listen().unwrap();
 }
@@ -0,0 +1,169 @@
+/*++ @file
+
+    Copyright ©2024-2024 Liu Yi, efikarl@yeah.net
+
+    This program is just made available under the terms and conditions of the
+    MIT license: http://www.efikarl.com/mit-license.html
+
+    THE PROGRAM IS DISTRIBUTED UNDER THE MIT LICENSE ON AN "AS IS" BASIS,
+    WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
+--*/
+
+// temporary files in progress, numbered to keep concurrent writes of a process apart
+static TEMPS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+const TEMP_SUFFIX: &str = ".tmp";
+
+// whether path is named as the temporary file of another, .{name}.{pid}.{n}.tmp
+pub fn is_temp<P: AsRef<std::path::Path>>(path: P) -> bool {
+    let Some(name) = path.as_ref().file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let Some(name) = name.strip_prefix('.').and_then(|name| name.strip_suffix(TEMP_SUFFIX)) else {
+        return false;
+    };
+    let mut items = name.rsplitn(3, '.');
+    let num = |item: Option<&str>| item.is_some_and(|item| !item.is_empty() && item.bytes().all(|b| b.is_ascii_digit()));
+    num(items.next()) && num(items.next()) && items.next().is_some_and(|item| !item.is_empty())
+}
+
+// a file written under a temporary name next to it and renamed to it once committed, so that the file
+// is either left as it was or complete. dropped before the commit, as on a failed transfer, the
+// temporary file is removed
+pub struct AtomicFile {
+    path: std::path::PathBuf,
+    temp: std::path::PathBuf,
+    file: Option<std::io::BufWriter<std::fs::File>>,
+    new : bool,
+    sync: bool,
+}
+
+impl AtomicFile {
+    // replace path, if any, once committed
+    pub fn create<P: AsRef<std::path::Path>>(path: P) -> Result<Self, std::io::Error> {
+        Self::open(path.as_ref(), false)
+    }
+
+    // create path once committed, failing with AlreadyExists if it exists by then or now
+    pub fn create_new<P: AsRef<std::path::Path>>(path: P) -> Result<Self, std::io::Error> {
+        if path.as_ref().symlink_metadata().is_ok() {
+            return Err(std::io::ErrorKind::AlreadyExists.into());
+        }
+        Self::open(path.as_ref(), true)
+    }
+
+    fn open(path: &std::path::Path, new: bool) -> Result<Self, std::io::Error> {
+        let name = path.file_name().ok_or(std::io::ErrorKind::InvalidInput)?.to_string_lossy();
+        let temp = path.with_file_name(format!(".{}.{}.{}{}", name, std::process::id(), TEMPS.fetch_add(1, std::sync::atomic::Ordering::Relaxed), TEMP_SUFFIX));
+        let file = std::fs::OpenOptions::new().write(true).create_new(true).open(&temp)?;
+        Ok(AtomicFile { path: path.to_path_buf(), temp, file: Some(std::io::BufWriter::new(file)), new, sync: false })
+    }
+
+    // sync the file to disk before the rename, and the rename itself after it
+    pub fn fsync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    // the end of the file: it replaces path, and later commits do nothing
+    pub fn commit(&mut self) -> Result<(), std::io::Error> {
+        match self.file.take() {
+            Some(file) => self.persist(file),
+            None => Ok(()),
+        }
+    }
+
+    fn persist(&mut self, file: std::io::BufWriter<std::fs::File>) -> Result<(), std::io::Error> {
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        if self.sync {
+            file.sync_all()?;
+        }
+        drop(file);
+        if self.new {
+            // a link fails on an existing file, where a rename would replace it
+            std::fs::hard_link(&self.temp, &self.path)?;
+            std::fs::remove_file(&self.temp)?;
+        } else {
+            std::fs::rename(&self.temp, &self.path)?;
+        }
+        #[cfg(unix)]
+        if self.sync {
+            if let Some(dir) = self.path.parent() {
+                std::fs::File::open(dir)?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::io::Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => Err(std::io::Error::other("EOR($): file committed")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        self.file.take();
+        std::fs::remove_file(&self.temp).unwrap_or_default();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_atomic() {
+    use std::io::Write;
+    let root = std::env::temp_dir().join(format!("tftp.atomic.{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let temps = || std::fs::read_dir(&root).unwrap().filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".tmp")).count();
+
+    // dropped before the commit, nothing is left
+    let mut file = AtomicFile::create(root.join("file")).unwrap();
+    file.write_all(b"half").unwrap();
+    assert_eq!(temps(), 1);
+    drop(file);
+    assert!(!root.join("file").exists());
+    assert_eq!(temps(), 0);
+    // the file appears on the commit only, not on a flush
+    let mut file = AtomicFile::create(root.join("file")).unwrap().fsync(true);
+    file.write_all(b"whole").unwrap();
+    file.flush().unwrap();
+    assert!(!root.join("file").exists());
+    file.commit().unwrap();
+    assert_eq!(std::fs::read(root.join("file")).unwrap(), b"whole");
+    assert!(file.write_all(b"more").is_err());
+    drop(file);
+    assert_eq!(std::fs::read(root.join("file")).unwrap(), b"whole");
+    // an existing file is kept until the commit
+    let mut file = AtomicFile::create(root.join("file")).unwrap();
+    file.write_all(b"other").unwrap();
+    assert_eq!(std::fs::read(root.join("file")).unwrap(), b"whole");
+    file.commit().unwrap();
+    assert_eq!(std::fs::read(root.join("file")).unwrap(), b"other");
+    // new files only
+    assert_eq!(AtomicFile::create_new(root.join("file")).err().unwrap().kind(), std::io::ErrorKind::AlreadyExists);
+    let mut file = AtomicFile::create_new(root.join("new")).unwrap();
+    std::fs::write(root.join("new"), b"first").unwrap();
+    assert_eq!(file.commit().unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+    drop(file);
+    assert_eq!(std::fs::read(root.join("new")).unwrap(), b"first");
+    assert_eq!(temps(), 0);
+    assert!(is_temp(".file.12.0.tmp"));
+    assert!(is_temp("dir/.a.b.1.23.tmp"));
+    assert!(!is_temp("file.12.0.tmp"));
+    assert!(!is_temp("..12.0.tmp"));
+    assert!(!is_temp(".file.x.0.tmp"));
+    assert!(!is_temp(".file.tmp"));
+    std::fs::remove_dir_all(&root).unwrap();
+}
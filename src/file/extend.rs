@@ -37,6 +37,6 @@ impl<P: AsRef<std::path::Path>> PathEx for P {
             }
         }
 
-        Ok(std::path::PathBuf::from(path))
+        Ok(path)
     }
 }
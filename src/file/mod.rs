@@ -10,3 +10,4 @@
 --*/
 
 pub mod extend;
+pub mod atomic;
@@ -11,5 +11,10 @@
 
 pub use std::io::prelude::*;
 pub use crate::file::extend::*;
+pub use crate::file::atomic::*;
 pub use crate::tftp::server::*;
+pub use crate::tftp::backend::*;
 pub use crate::tftp::client::*;
+pub use crate::tftp::error::*;
+#[cfg(feature = "async")]
+pub use crate::tftp::aio::*;
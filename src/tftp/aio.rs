@@ -0,0 +1,417 @@
+/*++ @file
+
+    Copyright ©2024-2024 Liu Yi, efikarl@yeah.net
+
+    This program is just made available under the terms and conditions of the
+    MIT license: http://www.efikarl.com/mit-license.html
+
+    THE PROGRAM IS DISTRIBUTED UNDER THE MIT LICENSE ON AN "AS IS" BASIS,
+    WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
+--*/
+
+use crate::file::extend::*;
+use crate::file::atomic::*;
+use crate::tftp::packet::*;
+use crate::tftp::error::*;
+use crate::tftp::transfer::*;
+use crate::tftp::server::*;
+use crate::tftp::client::*;
+use crate::tftp::netascii::*;
+use crate::tftp::backend::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+impl Socket for tokio::net::UdpSocket {
+    fn send_to(&self, buf: &[u8], peer: std::net::SocketAddr) -> impl std::future::Future<Output = Result<usize, std::io::Error>> + Send {
+        tokio::net::UdpSocket::send_to(self, buf, peer)
+    }
+
+    async fn recv_until(&self, buf: &mut [u8], until: std::time::Instant) -> Result<Option<(usize, std::net::SocketAddr)>, std::io::Error> {
+        match tokio::time::timeout_at(until.into(), self.recv_from(buf)).await {
+            Ok(rst) => rst.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+// bind a tokio socket as bind does
+fn bind_async(addr: std::net::SocketAddr, dualstack: Option<bool>) -> Result<tokio::net::UdpSocket, std::io::Error> {
+    let sock = bind(addr, dualstack)?;
+    sock.set_nonblocking(true)?;
+    tokio::net::UdpSocket::from_std(sock)
+}
+
+// a tokio reader or writer as the source or the sink of a transfer
+struct Tokio<T>(T);
+
+impl<R: tokio::io::AsyncRead + Unpin + Send> Source for Tokio<R> {
+    async fn fill(&mut self, blk: &mut [u8]) -> Result<usize, std::io::Error> {
+        let mut len = 0;
+        while len < blk.len() {
+            match self.0.read(&mut blk[len..]).await? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin + Send> Sink for Tokio<W> {
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
+        self.0.write_all(buf).await
+    }
+
+    fn finish(&mut self) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send {
+        self.0.flush()
+    }
+}
+
+// a blocking file driven from the runtime: each block is read or written on the blocking pool, the file
+// moving there and back along with a buffer of its own
+struct Offload<T: Send + 'static> {
+    file: Option<T>,
+    buf : Vec<u8>,
+}
+
+impl<T: Send + 'static> Offload<T> {
+    fn new(file: T) -> Self {
+        Offload { file: Some(file), buf: vec![] }
+    }
+
+    async fn run<U: Send + 'static, F: FnOnce(&mut T, &mut Vec<u8>) -> Result<U, std::io::Error> + Send + 'static>(&mut self, f: F) -> Result<U, std::io::Error> {
+        let mut file = self.file.take().ok_or(std::io::Error::other("EOR($): file lost"))?;
+        let mut buf = std::mem::take(&mut self.buf);
+        let (file, buf, rst) = tokio::task::spawn_blocking(move || {
+            let rst = f(&mut file, &mut buf);
+            (file, buf, rst)
+        }).await?;
+        self.file = Some(file);
+        self.buf = buf;
+        rst
+    }
+}
+
+impl<T: std::io::Read + Send + 'static> Source for Offload<T> {
+    async fn fill(&mut self, blk: &mut [u8]) -> Result<usize, std::io::Error> {
+        let len = blk.len();
+        let amt = self.run(move |file, buf| {
+            buf.resize(len, 0);
+            fill(file, buf)
+        }).await?;
+        blk[..amt].copy_from_slice(&self.buf[..amt]);
+        Ok(amt)
+    }
+}
+
+impl<T: TftpWriter + Send + 'static> Sink for Offload<T> {
+    fn write_all(&mut self, dat: &[u8]) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send {
+        self.buf.clear();
+        self.buf.extend_from_slice(dat);
+        self.run(|file, buf| file.write_all(buf))
+    }
+
+    fn finish(&mut self) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send {
+        self.run(|file, _| file.commit())
+    }
+}
+
+// a transfer over offloaded files, boxed where it starts: nested in a spawned task, the compiler cannot tell
+// its future Send otherwise
+fn boxed<'a, T>(fut: impl std::future::Future<Output = T> + Send + 'a) -> std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>> {
+    Box::pin(fut)
+}
+
+impl<T: Send + 'static> Drop for Offload<T> {
+    // closing the file may block too, as removing what an incomplete upload left
+    fn drop(&mut self) {
+        if let (Some(file), Ok(rt)) = (self.file.take(), tokio::runtime::Handle::try_current()) {
+            rt.spawn_blocking(move || drop(file));
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// the data phase of a transfer over tokio, behaving as Transfer does
+pub type AsyncTransfer<'a> = Transfer<'a, tokio::net::UdpSocket>;
+
+impl AsyncTransfer<'_> {
+    // send a reply to the request (ack or oack), retransmitted by recv on timeout
+    pub async fn reply(&mut self, pkt: Packet) -> Result<(), TftpError> {
+        self.answer(pkt).await
+    }
+
+    // send an oack to a rrq and wait for its ack
+    pub async fn oack(&mut self, oack: PacketOptions) -> Result<(), TftpError> {
+        self.offer(oack).await
+    }
+
+    pub async fn send<R: tokio::io::AsyncRead + Unpin + Send>(&mut self, src: &mut R) -> Result<u64, TftpError> {
+        self.push(&mut Tokio(src)).await
+    }
+
+    pub async fn recv<W: tokio::io::AsyncWrite + Unpin + Send>(&mut self, pending: Option<&[u8]>, quota: u64, dst: &mut W) -> Result<u64, TftpError> {
+        self.pull(pending, quota, &mut Tokio(dst)).await
+    }
+
+    // linger after recv, acknowledging again the last dat should the peer retransmit it
+    pub async fn linger(&mut self) {
+        self.dally().await
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// the server over tokio, configured as a Server. sessions are tasks owned by listen, so that
+// dropping its future cancels them all
+pub struct AsyncServer(std::sync::Arc<Server>);
+
+impl Default for AsyncServer {
+    fn default() -> Self {
+        Server::default().into()
+    }
+}
+
+impl From<Server> for AsyncServer {
+    fn from(server: Server) -> Self {
+        AsyncServer(std::sync::Arc::new(server))
+    }
+}
+
+impl AsyncServer {
+    pub fn new() -> Self {
+        Server::new().into()
+    }
+
+    pub async fn listen(&self) -> Result<(), std::io::Error> {
+        self.serve(bind_async(self.0.addr, self.0.dualstack)?).await
+    }
+
+    // serve requests arriving on svr, sessions are bound to its address
+    pub async fn serve(&self, svr: tokio::net::UdpSocket) -> Result<(), std::io::Error> {
+        let ip = svr.local_addr()?.ip();
+        let active = Active::default();
+        let mut sessions = tokio::task::JoinSet::new();
+        loop {
+            while sessions.try_join_next().is_some() {}
+            let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+            let (amt, clt) = match svr.recv_from(&mut raw).await {
+                Ok(rst) => rst,
+                Err(_)  => continue,
+            };
+            let pkt = match PacketRef::try_decode(&raw[0..amt]) {
+                Ok(pkt) => pkt,
+                Err(e)  => {
+                    if self.0.info {
+                        println!("Err(I): {}", e);
+                    }
+                    svr.send_to(&Packet::newerr(ErrorCode::IllegalOperation, e).encode(), clt).await.unwrap_or_default();
+                    continue;
+                }
+            };
+            match pkt {
+                PacketRef::Rrq(..) | PacketRef::Wrq(..) => {},
+                // an error is never answered
+                PacketRef::Err(..) => continue,
+                _ => {
+                    svr.send_to(&Packet::newerr(ErrorCode::IllegalOperation, "EOR($): request expected").encode(), clt).await.unwrap_or_default();
+                    continue;
+                },
+            }
+            let slot = match active.admit(clt, &raw[0..amt], self.0.sessions) {
+                Admit::Slot(slot) => slot,
+                Admit::Again => continue,
+                Admit::Busy => {
+                    if self.0.info {
+                        println!("Err(O): busy {}", clt);
+                    }
+                    svr.send_to(&Packet::newerr(ErrorCode::Undefined, "EOR($): server busy").encode(), clt).await.unwrap_or_default();
+                    continue;
+                },
+            };
+            let pkt = Packet::from(pkt);
+            let server = self.0.clone();
+            sessions.spawn(async move {
+                Self::session(server, ip, pkt, clt, slot).await.unwrap_or_default();
+            });
+        }
+    }
+
+    async fn session(server: std::sync::Arc<Server>, ip: std::net::IpAddr, pkt: Packet, clt: std::net::SocketAddr, slot: Slot) -> Result<(), TftpError> {
+        // handlers and backend block, as netascii does measuring a file
+        let session = {
+            let server = server.clone();
+            tokio::task::spawn_blocking(move || server.prepare(pkt, clt)).await.map_err(std::io::Error::from)?
+        };
+        let svr = bind_async((ip, TFTP_TID0).into(), server.dualstack)?;
+        boxed(server.run(&svr, clt, session.map(Offload::new, Offload::new), slot)).await
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// the client over tokio, local files being read and written on the blocking pool
+pub type AsyncClient = TftpClient<tokio::net::UdpSocket>;
+
+impl AsyncClient {
+    pub async fn new<A: tokio::net::ToSocketAddrs>(server: A) -> Result<Self, TftpError> {
+        let server_sa = tokio::net::lookup_host(server).await?.next().ok_or(std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))?;
+        let client_us = tokio::net::UdpSocket::bind(unspecified(&server_sa)).await?;
+        Ok(Self::with(server_sa, client_us))
+    }
+
+    pub async fn send<S: AsRef<std::path::Path>, D: AsRef<std::path::Path>>(&self, src: S, dst: D) -> Result<TransferStats, TftpError> {
+        let (mode, file) = (self.mode, src.as_ref().to_path_buf());
+        let size = tokio::task::spawn_blocking(move || mode.size(file)).await.map_err(std::io::Error::from)??;
+        let file = tokio::io::BufReader::new(tokio::fs::File::open(&src).await?);
+        self.upload(dst, file, Some(size)).await
+    }
+
+    pub async fn recv<S: AsRef<std::path::Path>, D: AsRef<std::path::Path>>(&self, src: S, dst: D) -> Result<TransferStats, TftpError> {
+        let (mode, sync, dst) = (self.mode, self.fsync, dst.as_ref().to_path_buf());
+        // the file is created once the server accepted the request, and replaces dst once complete only
+        let dst = || async move {
+            let file = tokio::task::spawn_blocking(move || -> Result<Box<dyn TftpWriter + Send>, std::io::Error> {
+                let file = AtomicFile::create(dst.try_create_parent(true)?)?.fsync(sync);
+                match mode {
+                    Mode::Octet    => Ok(Box::new(file)),
+                    Mode::Netascii => Ok(Box::new(Decoder::new(file))),
+                }
+            }).await??;
+            Ok(Offload::new(file))
+        };
+        boxed(self.get(src.as_ref(), dst)).await
+    }
+
+    // upload what src reads, of a size unknown to the server
+    pub async fn put_from_reader<D: AsRef<std::path::Path>, R: tokio::io::AsyncRead + Unpin + Send>(&self, dst: D, src: R) -> Result<TransferStats, TftpError> {
+        self.upload(dst, src, None).await
+    }
+
+    pub async fn get_to_writer<S: AsRef<std::path::Path>, W: tokio::io::AsyncWrite + Unpin + Send>(&self, src: S, dst: &mut W) -> Result<TransferStats, TftpError> {
+        let mode = self.mode;
+        let dst = || async move {
+            let dst: Box<dyn tokio::io::AsyncWrite + Unpin + Send + '_> = match mode {
+                Mode::Octet    => Box::new(dst),
+                Mode::Netascii => Box::new(Decoder::new(dst)),
+            };
+            Ok(Tokio(dst))
+        };
+        self.get(src, dst).await
+    }
+
+    async fn upload<D: AsRef<std::path::Path>, R: tokio::io::AsyncRead + Unpin + Send>(&self, dst: D, src: R, size: Option<u64>) -> Result<TransferStats, TftpError> {
+        match self.mode {
+            Mode::Octet    => self.put(dst, &mut Tokio(src), size).await,
+            Mode::Netascii => self.put(dst, &mut Tokio(Encoder::new(src)), size).await,
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_async_transfer() {
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        let opts = crate::tftp::option::Options { blksize: 8, windowsize: 4, ..Default::default() };
+        let dat = (0..100u8).collect::<Vec<u8>>();
+        let src = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dst = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src_sa = src.local_addr().unwrap();
+        let dst_sa = dst.local_addr().unwrap();
+        // packets of a third tid are refused and change nothing
+        let tid = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        tid.send_to(&Packet::newack(13).encode(), src_sa).await.unwrap();
+        tid.send_to(&Packet::newdat(1, vec![0xff;3]).encode(), dst_sa).await.unwrap();
+        let recv = tokio::spawn(async move {
+            let mut buf = vec![];
+            AsyncTransfer::new(&dst, src_sa, opts, false).recv(None, u64::MAX, &mut buf).await.unwrap();
+            buf
+        });
+        assert_eq!(AsyncTransfer::new(&src, dst_sa, opts, false).send(&mut dat.as_slice()).await.unwrap(), dat.len() as u64);
+        assert_eq!(recv.await.unwrap(), dat);
+        let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+        for _ in 0..2 {
+            let (amt, _) = tid.recv_from(&mut raw).await.unwrap();
+            assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(5, _)));
+        }
+    });
+}
+
+#[test]
+fn test_async_server_v6() {
+    let root = std::env::temp_dir().join(format!("tftp.async6.{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let dat = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+    std::fs::write(root.join("src"), &dat).unwrap();
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        let server = AsyncServer::from(Server::builder().bind(std::net::Ipv6Addr::LOCALHOST).port(0).root(&root).build());
+        let svr = tokio::net::UdpSocket::bind((std::net::Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = svr.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(svr).await });
+
+        let client = AsyncClient::new(addr).await.unwrap();
+        client.recv("src", root.join("dst")).await.unwrap();
+        assert_eq!(std::fs::read(root.join("dst")).unwrap(), dat);
+        client.send(root.join("dst"), "sub/dst").await.unwrap();
+        assert_eq!(std::fs::read(root.join("sub/dst")).unwrap(), dat);
+        client.put_from_reader("mem", dat.as_slice()).await.unwrap();
+        let mut buf = vec![];
+        client.get_to_writer("mem", &mut buf).await.unwrap();
+        assert_eq!(buf, dat);
+    });
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+
+#[test]
+fn test_async_cancel() {
+    let root = std::env::temp_dir().join(format!("tftp.cancel.{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    // files are dropped on the blocking pool, so give it a moment
+    async fn drained(root: &std::path::Path) -> bool {
+        for _ in 0..200 {
+            if std::fs::read_dir(root).unwrap().next().is_none() {
+                return true;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        false
+    }
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        // an upload in progress is removed once the server is dropped
+        let server = AsyncServer::from(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).port(0).root(&root).build());
+        let svr = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = svr.local_addr().unwrap();
+        let serve = tokio::spawn(async move { server.serve(svr).await });
+        let clt = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+        clt.send_to(&Packet::newwrq("up", "octet").encode(), addr).await.unwrap();
+        let (amt, tid) = clt.recv_from(&mut raw).await.unwrap();
+        assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Ack(0)));
+        clt.send_to(&Packet::newdat(1, vec![0x5a;512]).encode(), tid).await.unwrap();
+        let (amt, _) = clt.recv_from(&mut raw).await.unwrap();
+        assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Ack(1)));
+        assert!(std::fs::read_dir(&root).unwrap().next().is_some());
+        serve.abort();
+        let _ = serve.await;
+        assert!(drained(&root).await);
+
+        // so is a download in progress once the client gives up on it
+        let svr = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = svr.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_, clt) = svr.recv_from(&mut [0u8;TFTP_SIZE_PACKET_MAX]).await.unwrap();
+            svr.send_to(&Packet::newdat(1, vec![0x5a;512]).encode(), clt).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let client = AsyncClient::new(addr).await.unwrap();
+        let recv = tokio::time::timeout(std::time::Duration::from_millis(500), client.recv("src", root.join("down")));
+        assert!(recv.await.is_err());
+        assert!(drained(&root).await);
+    });
+    std::fs::remove_dir_all(&root).unwrap();
+}
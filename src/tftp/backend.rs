@@ -0,0 +1,298 @@
+/*++ @file
+
+    Copyright ©2024-2024 Liu Yi, efikarl@yeah.net
+
+    This program is just made available under the terms and conditions of the
+    MIT license: http://www.efikarl.com/mit-license.html
+
+    THE PROGRAM IS DISTRIBUTED UNDER THE MIT LICENSE ON AN "AS IS" BASIS,
+    WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
+--*/
+
+use crate::file::extend::*;
+use crate::file::atomic::*;
+
+// where the files of a server come from and go to. file is the name as requested by the client
+pub trait TftpBackend: Send + Sync {
+    // file to be read, with its size if known
+    fn open(&self, file: &str) -> Result<(Box<dyn std::io::Read + Send>, Option<u64>), std::io::Error>;
+    // file to be written, failing with AlreadyExists if it exists and new is set
+    fn create(&self, file: &str, new: bool) -> Result<Box<dyn TftpWriter + Send>, std::io::Error>;
+    fn exists(&self, file: &str) -> bool;
+    // the path below the root of the backend a file comes to, links resolved, for a policy to check
+    fn resolve(&self, file: &str) -> Result<std::path::PathBuf, std::io::Error> {
+        normalize(file)
+    }
+}
+
+// a file being written. it is committed once the transfer completes, dropped before the file is left as it was
+pub trait TftpWriter: std::io::Write {
+    fn commit(&mut self) -> Result<(), std::io::Error>;
+}
+
+impl<W: TftpWriter + ?Sized> TftpWriter for Box<W> {
+    fn commit(&mut self) -> Result<(), std::io::Error> {
+        (**self).commit()
+    }
+}
+
+impl TftpWriter for AtomicFile {
+    fn commit(&mut self) -> Result<(), std::io::Error> {
+        AtomicFile::commit(self)
+    }
+}
+
+// a requested file as a relative path, refusing absolute paths and parent references
+pub fn normalize(file: &str) -> Result<std::path::PathBuf, std::io::Error> {
+    let denied = || std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("EOR($): access violation: {}", file));
+    // clients of dos-like systems separate with backslash
+    if file.split(['/', '\\']).any(|item| item == "..") {
+        return Err(denied());
+    }
+    let mut path = std::path::PathBuf::new();
+    for item in std::path::Path::new(file).components() {
+        match item {
+            std::path::Component::Normal(name) => path.push(name),
+            std::path::Component::CurDir => {},
+            _ => return Err(denied()),
+        }
+    }
+    Ok(path)
+}
+
+// map a requested file into root, refusing absolute paths, parent references and symlinks out of it
+pub fn jail<P: AsRef<std::path::Path>>(root: P, file: &str) -> Result<std::path::PathBuf, std::io::Error> {
+    let root = root.as_ref().canonicalize()?;
+    real(&root, file).map(|(path, _)| path)
+}
+
+// the path below root a requested file resolves to, links of the part existing followed
+pub fn resolve<P: AsRef<std::path::Path>>(root: P, file: &str) -> Result<std::path::PathBuf, std::io::Error> {
+    let root = root.as_ref().canonicalize()?;
+    real(&root, file).map(|(_, real)| real)
+}
+
+// a requested file mapped into the canonical root, and the path below root it resolves to
+fn real(root: &std::path::Path, file: &str) -> Result<(std::path::PathBuf, std::path::PathBuf), std::io::Error> {
+    let denied = || std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("EOR($): access violation: {}", file));
+    let path = root.join(normalize(file)?);
+    // the part existing must resolve into root, a dangling symlink does not resolve at all
+    let mut part = path.as_path();
+    while part.symlink_metadata().is_err() {
+        part = part.parent().ok_or_else(denied)?;
+    }
+    match part.canonicalize() {
+        Ok(real) if real.starts_with(root) => {
+            let real = real.strip_prefix(root).map_err(|_| denied())?.join(path.strip_prefix(part).map_err(|_| denied())?);
+            Ok((path, real))
+        },
+        _ => Err(denied()),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// files below a root directory. files are written atomically, a failed upload leaving nothing behind.
+// the temporary files of uploads in progress are neither served nor taken
+pub struct Directory {
+    root : std::path::PathBuf,
+    fsync: bool,
+}
+
+impl Directory {
+    pub fn new<P: AsRef<std::path::Path>>(root: P) -> Self {
+        Directory { root: root.as_ref().to_path_buf(), fsync: false }
+    }
+
+    // sync uploads to disk before they replace the file
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    fn jail(&self, file: &str) -> Result<std::path::PathBuf, std::io::Error> {
+        match is_temp(file) {
+            true  => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("EOR($): file not found: {}", file))),
+            false => jail(&self.root, file),
+        }
+    }
+}
+
+impl TftpBackend for Directory {
+    fn open(&self, file: &str) -> Result<(Box<dyn std::io::Read + Send>, Option<u64>), std::io::Error> {
+        let file = std::fs::File::open(self.jail(file)?)?;
+        let size = file.metadata()?.len();
+        Ok((Box::new(std::io::BufReader::new(file)), Some(size)))
+    }
+
+    fn create(&self, file: &str, new: bool) -> Result<Box<dyn TftpWriter + Send>, std::io::Error> {
+        let file = self.jail(file)?.try_create_parent(true)?;
+        let file = match new {
+            true  => AtomicFile::create_new(file)?,
+            false => AtomicFile::create(file)?,
+        };
+        Ok(Box::new(file.fsync(self.fsync)))
+    }
+
+    fn exists(&self, file: &str) -> bool {
+        self.jail(file).is_ok_and(|file| file.symlink_metadata().is_ok())
+    }
+
+    fn resolve(&self, file: &str) -> Result<std::path::PathBuf, std::io::Error> {
+        resolve(&self.root, file)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// files in memory. clones share the files, so that what is written through a server can be read back
+#[derive(Debug, Default, Clone)]
+pub struct Memory {
+    files: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, Vec<u8>>>>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<D: Into<Vec<u8>>>(&self, file: &str, dat: D) -> Result<(), std::io::Error> {
+        self.files.lock().unwrap().insert(normalize(file)?, dat.into());
+        Ok(())
+    }
+
+    pub fn get(&self, file: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(&normalize(file).ok()?).cloned()
+    }
+}
+
+// a file being written, stored once committed at the end of the transfer
+struct MemoryFile {
+    name : std::path::PathBuf,
+    dat  : Vec<u8>,
+    files: Memory,
+}
+
+impl std::io::Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.dat.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TftpWriter for MemoryFile {
+    fn commit(&mut self) -> Result<(), std::io::Error> {
+        self.files.files.lock().unwrap().insert(self.name.clone(), self.dat.clone());
+        Ok(())
+    }
+}
+
+impl TftpBackend for Memory {
+    fn open(&self, file: &str) -> Result<(Box<dyn std::io::Read + Send>, Option<u64>), std::io::Error> {
+        let dat = self.files.lock().unwrap().get(&normalize(file)?).cloned().ok_or(std::io::ErrorKind::NotFound)?;
+        let size = dat.len() as u64;
+        Ok((Box::new(std::io::Cursor::new(dat)), Some(size)))
+    }
+
+    fn create(&self, file: &str, new: bool) -> Result<Box<dyn TftpWriter + Send>, std::io::Error> {
+        let name = normalize(file)?;
+        if new && self.files.lock().unwrap().contains_key(&name) {
+            return Err(std::io::ErrorKind::AlreadyExists.into());
+        }
+        Ok(Box::new(MemoryFile { name, dat: vec![], files: self.clone() }))
+    }
+
+    fn exists(&self, file: &str) -> bool {
+        normalize(file).is_ok_and(|name| self.files.lock().unwrap().contains_key(&name))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_jail() {
+    let root = std::env::temp_dir().join(format!("tftp.jail.{}", std::process::id()));
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("sub/file"), b"").unwrap();
+    let base = root.canonicalize().unwrap();
+
+    assert_eq!(jail(&root, "sub/file").unwrap(), base.join("sub/file"));
+    assert_eq!(jail(&root, "./sub/./file").unwrap(), base.join("sub/file"));
+    assert_eq!(jail(&root, "new/dir/file").unwrap(), base.join("new/dir/file"));
+    // parent references
+    assert!(jail(&root, "../file").is_err());
+    assert!(jail(&root, "sub/../../file").is_err());
+    assert!(jail(&root, "sub/../file").is_err());
+    assert!(jail(&root, "..\\..\\file").is_err());
+    // absolute paths
+    assert!(jail(&root, "/etc/passwd").is_err());
+    assert!(jail(&root, &base.join("sub/file").to_string_lossy()).is_err());
+    // symlinks out of root
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(std::env::temp_dir(), root.join("out")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", root.join("passwd")).unwrap();
+        std::os::unix::fs::symlink(std::env::temp_dir().join("tftp.jail.none"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink("sub", root.join("in")).unwrap();
+        assert!(jail(&root, "out/file").is_err());
+        assert!(jail(&root, "out/new/file").is_err());
+        assert!(jail(&root, "passwd").is_err());
+        assert!(jail(&root, "dangling").is_err());
+        assert_eq!(jail(&root, "in/file").unwrap(), base.join("in/file"));
+        assert_eq!(resolve(&root, "in/file").unwrap(), std::path::Path::new("sub/file"));
+        assert_eq!(resolve(&root, "in/new/file").unwrap(), std::path::Path::new("sub/new/file"));
+        assert!(resolve(&root, "out/file").is_err());
+    }
+    assert_eq!(resolve(&root, "./sub/new").unwrap(), std::path::Path::new("sub/new"));
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+
+#[test]
+fn test_memory() {
+    use std::io::{Read, Write};
+    let files = Memory::new();
+    files.insert("boot/host.ipxe", "#!ipxe\n").unwrap();
+    assert!(files.exists("./boot/host.ipxe"));
+    assert!(!files.exists("boot"));
+    let (mut src, size) = files.open("boot/host.ipxe").unwrap();
+    let mut buf = String::new();
+    src.read_to_string(&mut buf).unwrap();
+    assert_eq!((buf.as_str(), size), ("#!ipxe\n", Some(7)));
+    assert_eq!(files.open("none").err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(files.open("../boot/host.ipxe").err().unwrap().kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(files.create("boot/host.ipxe", true).err().unwrap().kind(), std::io::ErrorKind::AlreadyExists);
+    // written files show up once committed, in every clone
+    let mut dst = files.clone().create("up/cfg", false).unwrap();
+    dst.write_all(b"hostname sw1").unwrap();
+    dst.flush().unwrap();
+    assert_eq!(files.get("up/cfg"), None);
+    dst.commit().unwrap();
+    assert_eq!(files.get("up/cfg").unwrap(), b"hostname sw1");
+}
+
+#[test]
+fn test_directory() {
+    use std::io::Write;
+    let root = std::env::temp_dir().join(format!("tftp.directory.{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let files = Directory::new(&root);
+    let mut dst = files.create("up/cfg", false).unwrap();
+    dst.write_all(b"hostname sw1").unwrap();
+    dst.flush().unwrap();
+    assert!(!files.exists("up/cfg"));
+    // the temporary file of the upload is not to be seen
+    let temp = std::fs::read_dir(root.join("up")).unwrap().next().unwrap().unwrap().file_name();
+    let temp = format!("up/{}", temp.to_str().unwrap());
+    assert!(!files.exists(&temp));
+    assert_eq!(files.open(&temp).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(files.create(&temp, false).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    dst.commit().unwrap();
+    assert!(files.exists("up/cfg"));
+    assert_eq!(std::fs::read(root.join("up/cfg")).unwrap(), b"hostname sw1");
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -10,91 +10,416 @@
 --*/
 
 use crate::file::extend::*;
+use crate::file::atomic::*;
 use crate::tftp::packet::*;
+use crate::tftp::option::*;
+use crate::tftp::error::*;
+use crate::tftp::transfer::*;
+use crate::tftp::netascii::*;
+use crate::tftp::backend::*;
 
-pub struct Client { server_sa: std::net::SocketAddr, client_us: std::net::UdpSocket }
+// what a transfer did
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TransferStats {
+    pub bytes  : u64,
+    pub elapsed: std::time::Duration,
+    pub options: Options,
+}
 
-impl Client {
-    pub fn new<A: std::net::ToSocketAddrs>(server: A) -> Self {
-        let server_sa = server.to_socket_addrs().unwrap().next().unwrap();
-        let client_us = std::net::UdpSocket::bind(("0.0.0.0",0)).unwrap();
+// a progress callback gets the bytes done so far and the size of the file if known
+pub type Progress = Box<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+// a client over a socket: Client blocks, AsyncClient runs on tokio, and both share all but their
+// front ends
+pub struct TftpClient<S> {
+    pub(crate) server_sa: std::net::SocketAddr,
+    pub(crate) client_us: S,
+    pub(crate) options  : PacketOptions,
+    pub(crate) rollover : Rollover,
+    pub(crate) mode     : Mode,
+    pub(crate) timeout  : u64,
+    pub(crate) retries  : u32,
+    pub(crate) backoff  : u32,
+    pub(crate) deadline : Option<std::time::Duration>,
+    pub(crate) fsync    : bool,
+    pub(crate) dally    : bool,
+    pub(crate) info     : bool,
+    pub(crate) progress : Option<Progress>,
+}
+
+pub type Client = TftpClient<std::net::UdpSocket>;
+
+impl<S> TftpClient<S> {
+    pub(crate) fn with(server_sa: std::net::SocketAddr, client_us: S) -> Self {
+        TftpClient { server_sa, client_us, options: PacketOptions::new(), rollover: Rollover::default(), mode: Mode::default(),
+            timeout: TFTP_TIMEOUT, retries: TFTP_RETRIES, backoff: TFTP_BACKOFF, deadline: None, fsync: false, dally: false, info: false, progress: None }
+    }
+
+    pub fn option<N: ToString, V: ToString>(&mut self, name: N, value: V) -> &mut Self {
+        let name = name.to_string().to_lowercase();
+        self.options.retain(|(n, _)| *n != name);
+        self.options.push((name, value.to_string()));
+        self
+    }
+
+    pub fn rollover(&mut self, rollover: Rollover) -> &mut Self {
+        self.rollover = rollover;
+        self
+    }
+
+    pub fn mode(&mut self, mode: Mode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    // seconds to wait for a packet before a retransmission, unless the timeout option is negotiated
+    pub fn timeout(&mut self, timeout: u64) -> &mut Self {
+        self.timeout = timeout.clamp(TFTP_TIMEOUT_MIN, TFTP_TIMEOUT_MAX);
+        self
+    }
+
+    pub fn retry(&mut self, retries: u32, backoff: u32) -> &mut Self {
+        self.retries = retries;
+        self.backoff = std::cmp::max(backoff, 1);
+        self
+    }
+
+    // time a whole transfer may take
+    pub fn deadline(&mut self, deadline: std::time::Duration) -> &mut Self {
+        self.deadline = Some(deadline);
+        self
+    }
 
-        Client { server_sa, client_us }
+    // sync received files to disk before they replace the destination
+    pub fn fsync(&mut self, fsync: bool) -> &mut Self {
+        self.fsync = fsync;
+        self
     }
 
-    pub fn send<S: AsRef<std::path::Path>, D: AsRef<std::path::Path>>(&self, src: S, dst: D) {
-        let mut svr = self.server_sa.clone();
-        // init port:TFTP_PORT
-        svr.set_port(TFTP_PORT);
+    // linger after the last ack of a download, acknowledging again the dat the server retransmits if it
+    // lost that ack. this holds the caller for an interval and a half, so it is off by default
+    pub fn dally(&mut self, dally: bool) -> &mut Self {
+        self.dally = dally;
+        self
+    }
+
+    // trace the packets of transfers
+    pub fn info(&mut self, info: bool) -> &mut Self {
+        self.info = info;
+        self
+    }
+
+    // told the bytes done so far and the size of the file if known, as a transfer goes
+    pub fn progress<F: Fn(u64, Option<u64>) + Send + Sync + 'static>(&mut self, progress: F) -> &mut Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    fn report(&self, len: u64, size: Option<u64>) {
+        if let Some(progress) = &self.progress {
+            progress(len, size);
+        }
+    }
+
+}
+
+impl<S: Socket> TftpClient<S> {
+    // take the options of an oack, rejecting it to the server if not acceptable
+    async fn accept(&self, oack: &PacketOptions, svr: std::net::SocketAddr) -> Result<Options, TftpError> {
+        match accept(&self.options, oack) {
+            Ok(mut opts) => {
+                if option(oack, TFTP_OPTION_TIMEOUT).is_none() {
+                    opts.timeout = self.timeout;
+                }
+                Ok(opts)
+            },
+            Err(e) => {
+                self.client_us.send_to(&Packet::newerr(ErrorCode::OptionRefused, &e).encode(), svr).await.unwrap_or_default();
+                Err(TftpError::Protocol(e.to_string()))
+            },
+        }
+    }
+
+    // send a request and recv the first answer to it, retransmitting the request on timeout. the answer
+    // comes from a tid of the server, one of any other host is told error 5 and dropped
+    async fn request(&self, req: &[u8], svr: std::net::SocketAddr, buf: &mut [u8], deadline: Option<std::time::Instant>) -> Result<(usize, std::net::SocketAddr), TftpError> {
+        let mut interval = std::time::Duration::from_secs(self.timeout);
+        for _ in 0..=self.retries {
+            self.client_us.send_to(req, svr).await?;
+            let until = std::time::Instant::now() + remain(interval, deadline)?;
+            while let Some((amt, peer)) = self.client_us.recv_until(buf, until).await? {
+                if peer.ip() == svr.ip() {
+                    return Ok((amt, peer));
+                }
+                if self.info {
+                    println!("Tid(I): {} != {}", peer, svr.ip());
+                }
+                self.client_us.send_to(&Packet::newerr(ErrorCode::UnknownTid, "EOR($): unknown tid").encode(), peer).await.unwrap_or_default();
+            }
+            expire(deadline)?;
+            interval *= self.backoff;
+        }
+        Err(TftpError::Timeout)
+    }
+
+    // upload src, of size if known to the server
+    pub(crate) async fn put<D: AsRef<std::path::Path>, R: Source>(&self, dst: D, src: &mut R, size: Option<u64>) -> Result<TransferStats, TftpError> {
+        let time = std::time::Instant::now();
+        let deadline = self.deadline.map(|deadline| time + deadline);
+        let mut svr = self.server_sa;
+        // init port:TFTP_PORT, unless another one is given
+        if svr.port() == 0 {
+            svr.set_port(TFTP_PORT);
+        }
         // send wrq
-        let wrq = Packet::newwrq(&dst, TFTP_MODE).encode();
-        self.client_us.send_to(&wrq, svr).unwrap();
-        // recv ack
+        let wrq = Packet::newwrq(&dst, self.mode.name()).with_options(request(&self.options, size)).encode();
+        // recv ack or oack
         let mut ack = [0u8;TFTP_SIZE_PACKET_MAX];
-        (_, svr) = self.client_us.recv_from(&mut ack).unwrap();
-        assert_eq!(
-            0,
-            u16::from_be_bytes([ack[2], ack[3]])
-        );
+        let amt;
+        (amt, svr) = self.request(&wrq, svr, &mut ack, deadline).await?;
+        let opts = match Packet::try_decode(&ack[..amt])? {
+            Packet::Ack(0) => Options { timeout: self.timeout, ..Options::default() },
+            Packet::Oack(oack) => self.accept(&oack, svr).await?,
+            Packet::Err(code, msgs) => return Err(TftpError::Remote(code, msgs)),
+            pkt => return Err(TftpError::Protocol(format!("EOR($): {:?}", pkt.opcode()))),
+        };
         // send dat
-        let mut buf;
-        let mut dat = std::fs::read(&src).unwrap();
-        let mut len = dat.len();
-        let mut blk = 1;
-        loop {
-            if len <= 0 {
-                break;
-            }
-            if len <= TFTP_SIZE_DATA_BLOCK {
-                self.client_us.send_to(&Packet::newdat(blk, dat).encode(), svr).unwrap();
-                self.client_us.recv_from(&mut ack).unwrap();
-                assert_eq!(
-                    blk,
-                    u16::from_be_bytes([ack[2], ack[3]])
-                );
-                break;
-            } else {
-                buf = dat.split_off(TFTP_SIZE_DATA_BLOCK);
-                self.client_us.send_to(&Packet::newdat(blk, dat).encode(), svr).unwrap();
-                (_, svr) = self.client_us.recv_from(&mut ack).unwrap();
-                assert_eq!(
-                    blk,
-                    u16::from_be_bytes([ack[2], ack[3]])
-                );
-                dat = buf;
-
-                blk = blk + 1;
-                len = len - TFTP_SIZE_DATA_BLOCK;
-            }
-        }
+        let report = |len| self.report(len, size);
+        let bytes = Transfer::new(&self.client_us, svr, opts, self.info).retry(self.retries, self.backoff).rollover(self.rollover).deadline(deadline).report(&report).push(src).await?;
+        Ok(TransferStats { bytes, elapsed: time.elapsed(), options: opts })
     }
 
-    pub fn recv<S: AsRef<std::path::Path>, D: AsRef<std::path::Path>>(&self, src: S, dst: D) {
-        let mut svr = self.server_sa.clone();
-        // init port:TFTP_PORT
-        svr.set_port(TFTP_PORT);
-        let mut amt;
+    // download src into what dst makes, once the server accepted the request
+    pub(crate) async fn get<P, W, F, O>(&self, src: P, dst: F) -> Result<TransferStats, TftpError>
+    where
+        P: AsRef<std::path::Path>,
+        W: Sink,
+        F: FnOnce() -> O,
+        O: std::future::Future<Output = Result<W, std::io::Error>>,
+    {
+        let time = std::time::Instant::now();
+        let deadline = self.deadline.map(|deadline| time + deadline);
+        let mut svr = self.server_sa;
+        // init port:TFTP_PORT, unless another one is given
+        if svr.port() == 0 {
+            svr.set_port(TFTP_PORT);
+        }
+        let amt;
         // send rrq
-        let mut rrq = Packet::newrrq(&src, TFTP_MODE).encode();
-        self.client_us.send_to(&mut rrq, svr).unwrap();
+        let rrq = Packet::newrrq(&src, self.mode.name()).with_options(request(&self.options, Some(0))).encode();
+        // recv oack or dat
+        let mut dat = vec![0u8;TFTP_SIZE_BLOCK_MAX + 4];
+        (amt, svr) = self.request(&rrq, svr, &mut dat, deadline).await?;
+        let (opts, pkt) = match PacketRef::try_decode(&dat[..amt])? {
+            PacketRef::Oack(oack) => (self.accept(&oack.into(), svr).await?, None),
+            PacketRef::Err(code, msgs) => return Err(TftpError::Remote(code, String::from_utf8_lossy(msgs).into_owned())),
+            PacketRef::Dat(..) => (Options { timeout: self.timeout, ..Options::default() }, Some(&dat[..amt])),
+            pkt => return Err(TftpError::Protocol(format!("EOR($): {:?}", pkt.opcode()))),
+        };
+        let mut dst = match dst().await {
+            Ok(dst) => dst,
+            Err(e) => {
+                self.client_us.send_to(&Packet::newerr(ErrorCode::from(&e), "EOR($): write").encode(), svr).await.unwrap_or_default();
+                return Err(e.into());
+            },
+        };
+        let report = |len| self.report(len, opts.tsize);
+        let mut transfer = Transfer::new(&self.client_us, svr, opts, self.info).retry(self.retries, self.backoff).rollover(self.rollover).deadline(deadline).report(&report);
+        if pkt.is_none() {
+            transfer.answer(Packet::newack(0)).await?;
+        }
         // recv dat
-        let mut buf = vec![];
-        let mut dat = [0u8;TFTP_SIZE_PACKET_MAX];
-        let mut blk = 1;
-        loop {
-            (amt, svr) = self.client_us.recv_from(&mut dat).unwrap();
-            assert_eq!(
-                blk,
-                u16::from_be_bytes([dat[2], dat[3]])
-            );
-            self.client_us.send_to(&Packet::newack(blk).encode(), svr).unwrap();
-            buf.extend_from_slice(&dat[4..amt]);
-            if amt < TFTP_SIZE_PACKET_MAX {
-                break;
+        let bytes = transfer.pull(pkt, u64::MAX, &mut dst).await?;
+        let stats = TransferStats { bytes, elapsed: time.elapsed(), options: opts };
+        if self.dally {
+            transfer.dally().await;
+        }
+        Ok(stats)
+    }
+}
+
+// the client over a blocking socket
+impl Client {
+    pub fn new<A: std::net::ToSocketAddrs>(server: A) -> Result<Self, TftpError> {
+        let server_sa = server.to_socket_addrs()?.next().ok_or(std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))?;
+        let client_us = std::net::UdpSocket::bind(unspecified(&server_sa))?;
+        Ok(Self::with(server_sa, client_us))
+    }
+
+    pub fn send<S: AsRef<std::path::Path>, D: AsRef<std::path::Path>>(&self, src: S, dst: D) -> Result<TransferStats, TftpError> {
+        let size = self.mode.size(&src)?;
+        let file = std::io::BufReader::new(std::fs::File::open(&src)?);
+        self.upload(dst, file, Some(size))
+    }
+
+    pub fn recv<S: AsRef<std::path::Path>, D: AsRef<std::path::Path>>(&self, src: S, dst: D) -> Result<TransferStats, TftpError> {
+        // the file is created once the server accepted the request, and replaces dst once complete only
+        self.download(src, || Ok(AtomicFile::create(dst.try_create_parent(true)?)?.fsync(self.fsync)))
+    }
+
+    // upload what src reads, of a size unknown to the server
+    pub fn put_from_reader<D: AsRef<std::path::Path>, R: std::io::Read>(&self, dst: D, src: R) -> Result<TransferStats, TftpError> {
+        self.upload(dst, src, None)
+    }
+
+    pub fn get_to_writer<S: AsRef<std::path::Path>, W: std::io::Write>(&self, src: S, dst: &mut W) -> Result<TransferStats, TftpError> {
+        self.download(src, || Ok(Flushed(dst)))
+    }
+
+    fn upload<D: AsRef<std::path::Path>, R: std::io::Read>(&self, dst: D, src: R, size: Option<u64>) -> Result<TransferStats, TftpError> {
+        match self.mode {
+            Mode::Octet    => block_on(self.put(dst, &mut Blocking(src), size)),
+            Mode::Netascii => block_on(self.put(dst, &mut Blocking(Encoder::new(src)), size)),
+        }
+    }
+
+    fn download<'a, S: AsRef<std::path::Path>, W: TftpWriter + 'a, F: FnOnce() -> Result<W, std::io::Error>>(&self, src: S, dst: F) -> Result<TransferStats, TftpError> {
+        let dst = || std::future::ready(dst().map(|dst| -> Blocking<Box<dyn TftpWriter + 'a>> {
+            match self.mode {
+                Mode::Octet    => Blocking(Box::new(dst)),
+                Mode::Netascii => Blocking(Box::new(Decoder::new(dst))),
             }
-            blk = blk + 1;
+        }));
+        block_on(self.get(src, dst))
+    }
+}
+
+// the any address of the family of the server, with an ephemeral port
+pub(crate) fn unspecified(server_sa: &std::net::SocketAddr) -> std::net::SocketAddr {
+    match server_sa {
+        std::net::SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        std::net::SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+#[test]
+fn test_client_error() {
+    let svr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(svr.local_addr().unwrap()).unwrap();
+    assert!(matches!(client.send("tftp.none", "dst"), Err(TftpError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound));
+    let peer = std::thread::spawn(move || {
+        let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+        let (_, clt) = svr.recv_from(&mut raw).unwrap();
+        svr.send_to(&Packet::newerr(ErrorCode::AccessViolation, "EOR($): access violation").encode(), clt).unwrap();
+        let (_, clt) = svr.recv_from(&mut raw).unwrap();
+        svr.send_to(&Packet::newack(5).encode(), clt).unwrap();
+        let (_, clt) = svr.recv_from(&mut raw).unwrap();
+        svr.send_to(&Packet::newack(0).encode(), clt).unwrap();
+        let (amt, _) = svr.recv_from(&mut raw).unwrap();
+        Packet::try_decode(&raw[..amt]).unwrap()
+    });
+    assert!(matches!(client.recv("src", "dst"), Err(TftpError::Remote(2, msgs)) if msgs == "EOR($): access violation"));
+    assert!(matches!(client.recv("src", "dst"), Err(TftpError::Protocol(_))));
+    // a local error is told as such, whatever its kind
+    struct Stall;
+    impl std::io::Read for Stall {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize, std::io::Error> {
+            Err(std::io::Error::from(std::io::ErrorKind::TimedOut))
         }
-        let file = dst.try_create_parent(true).unwrap();
-        std::fs::write(&file, buf).unwrap();
     }
+    assert!(matches!(client.put_from_reader("dst", Stall), Err(TftpError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut));
+    assert!(matches!(peer.join().unwrap(), Packet::Err(0, _)));
+}
+
+#[test]
+fn test_client_timeout() {
+    // a server that never answers gets the request retries + 1 times
+    let svr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    svr.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+    let mut client = Client::new(svr.local_addr().unwrap()).unwrap();
+    client.timeout(1).retry(2, 1);
+    let peer = std::thread::spawn(move || {
+        let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+        let mut n = 0;
+        while let Ok((amt, _)) = svr.recv_from(&mut raw) {
+            assert!(matches!(Packet::try_decode(&raw[..amt]), Ok(Packet::Rrq(..))));
+            n += 1;
+        }
+        n
+    });
+    assert!(matches!(client.recv("src", "dst"), Err(TftpError::Timeout)));
+    assert_eq!(peer.join().unwrap(), 3);
+    // a server dying mid transfer gets the last ack again, until the deadline
+    let svr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut client = Client::new(svr.local_addr().unwrap()).unwrap();
+    client.timeout(1).retry(10, 1).deadline(std::time::Duration::from_millis(2500));
+    let peer = std::thread::spawn(move || {
+        let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+        let (_, clt) = svr.recv_from(&mut raw).unwrap();
+        svr.send_to(&Packet::newdat(1, vec![0u8;TFTP_SIZE_DATA_BLOCK]).encode(), clt).unwrap();
+        svr.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+        let mut acks = vec![];
+        while let Ok((amt, _)) = svr.recv_from(&mut raw) {
+            acks.push(Packet::try_decode(&raw[..amt]).unwrap());
+        }
+        acks
+    });
+    let time = std::time::Instant::now();
+    let dst = std::env::temp_dir().join(format!("tftp.timeout.{}", std::process::id()));
+    assert!(matches!(client.recv("src", &dst), Err(TftpError::Timeout)));
+    assert!(time.elapsed() < std::time::Duration::from_secs(4));
+    let acks = peer.join().unwrap();
+    assert_eq!(acks.len(), 3);
+    assert!(acks.iter().all(|ack| *ack == Packet::newack(1)));
+    // nor is anything of the failed transfer left
+    assert!(!dst.exists());
+}
+
+#[test]
+fn test_client_tid() {
+    // another host answering the request first is refused, and the server still served
+    let svr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let tid = std::net::UdpSocket::bind("127.0.0.2:0").unwrap();
+    tid.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+    let client = Client::new(svr.local_addr().unwrap()).unwrap();
+    let peer = std::thread::spawn(move || {
+        let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+        let (_, clt) = svr.recv_from(&mut raw).unwrap();
+        tid.send_to(&Packet::newdat(1, b"fake".to_vec()).encode(), clt).unwrap();
+        let (amt, _) = tid.recv_from(&mut raw).unwrap();
+        assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(5, _)));
+        svr.send_to(&Packet::newdat(1, b"real".to_vec()).encode(), clt).unwrap();
+        let (amt, _) = svr.recv_from(&mut raw).unwrap();
+        Packet::try_decode(&raw[..amt]).unwrap()
+    });
+    let mut buf = vec![];
+    client.get_to_writer("src", &mut buf).unwrap();
+    assert_eq!(buf, b"real");
+    assert_eq!(peer.join().unwrap(), Packet::newack(1));
+}
+
+#[test]
+fn test_client_stream() {
+    let root = std::env::temp_dir().join(format!("tftp.stream.{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let mut client = Client::new(crate::tftp::server::spawn(crate::tftp::server::Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).root(&root))).unwrap();
+
+    let dat = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+    client.option("tsize", 0);
+    assert_eq!(client.put_from_reader("dat", dat.as_slice()).unwrap().bytes, dat.len() as u64);
+    let mut buf = vec![];
+    let stats = client.get_to_writer("dat", &mut buf).unwrap();
+    assert_eq!((stats.bytes, stats.options.tsize), (dat.len() as u64, Some(dat.len() as u64)));
+    assert_eq!(buf, dat);
+    // a remote error leaves the writer untouched
+    let mut buf = vec![];
+    assert!(matches!(client.get_to_writer("../dat", &mut buf), Err(TftpError::Remote(2, _))));
+    assert!(buf.is_empty());
+    client.mode(Mode::Netascii);
+    client.put_from_reader("txt", &b"a\nb\r"[..]).unwrap();
+    assert_eq!(std::fs::read(root.join("txt")).unwrap(), b"a\nb\r");
+    let mut buf = vec![];
+    assert_eq!(client.get_to_writer("txt", &mut buf).unwrap().bytes, 6);
+    assert_eq!(buf, b"a\nb\r");
+    // progress of whole blocks, up to the size told by tsize
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let tell = seen.clone();
+    client.mode(Mode::Octet).progress(move |len, size| tell.lock().unwrap().push((len, size)));
+    client.get_to_writer("dat", &mut vec![]).unwrap();
+    let size = Some(dat.len() as u64);
+    assert_eq!(*seen.lock().unwrap(), (1..=5).map(|n| (n * 512, size)).chain([(3000, size)]).collect::<Vec<_>>());
+    seen.lock().unwrap().clear();
+    client.send(root.join("dat"), "copy").unwrap();
+    assert_eq!(seen.lock().unwrap().last(), Some(&(3000, size)));
+    std::fs::remove_dir_all(&root).unwrap();
 }
@@ -0,0 +1,168 @@
+/*++ @file
+
+    Copyright ©2024-2024 Liu Yi, efikarl@yeah.net
+
+    This program is just made available under the terms and conditions of the
+    MIT license: http://www.efikarl.com/mit-license.html
+
+    THE PROGRAM IS DISTRIBUTED UNDER THE MIT LICENSE ON AN "AS IS" BASIS,
+    WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
+--*/
+
+// error codes of an error packet, rfc 1350 and rfc 2347 for 8
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    Undefined        = 0x00,
+    FileNotFound     = 0x01,
+    AccessViolation  = 0x02,
+    DiskFull         = 0x03,
+    IllegalOperation = 0x04,
+    UnknownTid       = 0x05,
+    FileExists       = 0x06,
+    NoSuchUser       = 0x07,
+    OptionRefused    = 0x08,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCode::Undefined        => write!(f, "not defined"),
+            ErrorCode::FileNotFound     => write!(f, "file not found"),
+            ErrorCode::AccessViolation  => write!(f, "access violation"),
+            ErrorCode::DiskFull         => write!(f, "disk full or allocation exceeded"),
+            ErrorCode::IllegalOperation => write!(f, "illegal tftp operation"),
+            ErrorCode::UnknownTid       => write!(f, "unknown transfer id"),
+            ErrorCode::FileExists       => write!(f, "file already exists"),
+            ErrorCode::NoSuchUser       => write!(f, "no such user"),
+            ErrorCode::OptionRefused    => write!(f, "option negotiation refused"),
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        code as u16
+    }
+}
+
+// codes out of 0..=8 are taken as not defined
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            0x01 => ErrorCode::FileNotFound,
+            0x02 => ErrorCode::AccessViolation,
+            0x03 => ErrorCode::DiskFull,
+            0x04 => ErrorCode::IllegalOperation,
+            0x05 => ErrorCode::UnknownTid,
+            0x06 => ErrorCode::FileExists,
+            0x07 => ErrorCode::NoSuchUser,
+            0x08 => ErrorCode::OptionRefused,
+            _    => ErrorCode::Undefined,
+        }
+    }
+}
+
+// the code telling a peer why its request or transfer failed
+impl From<std::io::ErrorKind> for ErrorCode {
+    fn from(kind: std::io::ErrorKind) -> Self {
+        match kind {
+            std::io::ErrorKind::NotFound         => ErrorCode::FileNotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorCode::AccessViolation,
+            std::io::ErrorKind::StorageFull      => ErrorCode::DiskFull,
+            std::io::ErrorKind::AlreadyExists    => ErrorCode::FileExists,
+            _ => ErrorCode::Undefined,
+        }
+    }
+}
+
+impl From<&std::io::Error> for ErrorCode {
+    fn from(e: &std::io::Error) -> Self {
+        ErrorCode::from(e.kind())
+    }
+}
+
+// how a transfer failed, as told to the user of a client
+#[derive(Debug)]
+pub enum TftpError {
+    Io(std::io::Error),
+    // the peer did not answer in time, retransmissions included
+    Timeout,
+    // the peer sent something it must not send
+    Protocol(String),
+    // the peer aborted with an error packet, of a code that may be out of ErrorCode
+    Remote(u16, String),
+}
+
+impl TftpError {
+    // the code of a remote error
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            TftpError::Remote(code, _) => Some(ErrorCode::from(*code)),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for TftpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TftpError::Io(e)              => write!(f, "i/o error: {}", e),
+            TftpError::Timeout            => write!(f, "timeout"),
+            TftpError::Protocol(msgs)     => write!(f, "protocol error: {}", msgs),
+            TftpError::Remote(code, msgs) => write!(f, "remote error {}: {}", code, msgs),
+        }
+    }
+}
+
+impl std::error::Error for TftpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TftpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// a local i/o error, whatever its kind: transfers tell timeouts and what the peer did themselves
+impl From<std::io::Error> for TftpError {
+    fn from(e: std::io::Error) -> Self {
+        TftpError::Io(e)
+    }
+}
+
+impl From<crate::tftp::packet::PacketError> for TftpError {
+    fn from(e: crate::tftp::packet::PacketError) -> Self {
+        TftpError::Protocol(e.to_string())
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_error() {
+    // local errors stay i/o errors, whatever their kind
+    for kind in [std::io::ErrorKind::TimedOut, std::io::ErrorKind::InvalidData, std::io::ErrorKind::ConnectionAborted, std::io::ErrorKind::NotFound] {
+        let e = std::io::Error::new(kind, "EOR($): local");
+        assert!(matches!(TftpError::from(e), TftpError::Io(e) if e.kind() == kind));
+    }
+    let e = crate::tftp::packet::PacketError::Truncated(3);
+    assert!(matches!(TftpError::from(e), TftpError::Protocol(msgs) if msgs == "truncated packet: 3 bytes"));
+    assert_eq!(TftpError::Remote(1, String::from("file not found")).to_string(), "remote error 1: file not found");
+}
+
+#[test]
+fn test_error_code() {
+    for code in 0..=8u16 {
+        assert_eq!(u16::from(ErrorCode::from(code)), code);
+    }
+    assert_eq!(ErrorCode::from(9), ErrorCode::Undefined);
+    assert_eq!(ErrorCode::from(std::io::ErrorKind::NotFound), ErrorCode::FileNotFound);
+    assert_eq!(ErrorCode::from(std::io::ErrorKind::PermissionDenied), ErrorCode::AccessViolation);
+    assert_eq!(ErrorCode::from(std::io::ErrorKind::StorageFull), ErrorCode::DiskFull);
+    assert_eq!(ErrorCode::from(std::io::ErrorKind::AlreadyExists), ErrorCode::FileExists);
+    assert_eq!(ErrorCode::from(std::io::ErrorKind::Interrupted), ErrorCode::Undefined);
+    assert_eq!(TftpError::Remote(6, String::new()).code(), Some(ErrorCode::FileExists));
+    assert_eq!(TftpError::Timeout.code(), None);
+}
@@ -10,5 +10,12 @@
 --*/
 
 pub mod packet;
+pub mod error;
+pub mod option;
+pub mod netascii;
+pub mod transfer;
+pub mod backend;
 pub mod server;
 pub mod client;
+#[cfg(feature = "async")]
+pub mod aio;
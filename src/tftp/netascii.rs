@@ -0,0 +1,266 @@
+/*++ @file
+
+    Copyright ©2024-2024 Liu Yi, efikarl@yeah.net
+
+    This program is just made available under the terms and conditions of the
+    MIT license: http://www.efikarl.com/mit-license.html
+
+    THE PROGRAM IS DISTRIBUTED UNDER THE MIT LICENSE ON AN "AS IS" BASIS,
+    WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
+--*/
+
+use std::io::Read;
+
+// netascii (rfc764) as a streaming transform: a local LF is CR LF on the wire and a local CR is CR NUL
+
+pub const TFTP_MODE_OCTET       : & str =                  "octet";
+pub const TFTP_MODE_NETASCII    : & str =               "netascii";
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Mode {
+    #[default]
+    Octet,
+    Netascii,
+}
+
+impl Mode {
+    // the mode of a request, none if we do not support it
+    pub fn from_name(name: &str) -> Option<Mode> {
+        if name.eq_ignore_ascii_case(TFTP_MODE_OCTET) {
+            Some(Mode::Octet)
+        } else if name.eq_ignore_ascii_case(TFTP_MODE_NETASCII) {
+            Some(Mode::Netascii)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Octet    => TFTP_MODE_OCTET,
+            Mode::Netascii => TFTP_MODE_NETASCII,
+        }
+    }
+
+    // size of file on the wire in this mode
+    pub fn size<P: AsRef<std::path::Path>>(&self, file: P) -> Result<u64, std::io::Error> {
+        match self {
+            Mode::Octet    => Ok(std::fs::metadata(file)?.len()),
+            Mode::Netascii => self.measure(std::fs::File::open(file)?),
+        }
+    }
+
+    // size on the wire in this mode of what src reads
+    pub fn measure<R: std::io::Read>(&self, src: R) -> Result<u64, std::io::Error> {
+        let mut len = 0;
+        for byte in std::io::BufReader::new(src).bytes() {
+            len += match (self, byte?) {
+                (Mode::Netascii, b'\n' | b'\r') => 2,
+                _ => 1,
+            };
+        }
+        Ok(len)
+    }
+}
+
+fn encode(src: &[u8], dst: &mut Vec<u8>) {
+    for &byte in src {
+        match byte {
+            b'\n' => dst.extend_from_slice(b"\r\n"),
+            b'\r' => dst.extend_from_slice(b"\r\0"),
+            byte  => dst.push(byte),
+        }
+    }
+}
+
+// cr: a CR ended the last chunk and is waiting for the byte telling what it stands for
+fn decode(cr: &mut bool, src: &[u8], dst: &mut Vec<u8>) {
+    for &byte in src {
+        if std::mem::take(cr) {
+            match byte {
+                b'\n' => dst.push(b'\n'),
+                b'\0' => dst.push(b'\r'),
+                b'\r' => {
+                    dst.push(b'\r');
+                    *cr = true;
+                },
+                byte  => dst.extend_from_slice(&[b'\r', byte]),
+            }
+        } else if byte == b'\r' {
+            *cr = true;
+        } else {
+            dst.push(byte);
+        }
+    }
+}
+
+// reads local text from inner as netascii
+pub struct Encoder<R> {
+    inner: R,
+    out  : std::collections::VecDeque<u8>,
+}
+
+impl<R> Encoder<R> {
+    pub fn new(inner: R) -> Self {
+        Encoder { inner, out: std::collections::VecDeque::new() }
+    }
+
+    fn drain(&mut self, buf: &mut [u8]) -> usize {
+        let len = std::cmp::min(buf.len(), self.out.len());
+        for (i, byte) in self.out.drain(..len).enumerate() {
+            buf[i] = byte;
+        }
+        len
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for Encoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.out.is_empty() && !buf.is_empty() {
+            let mut raw = vec![0u8;buf.len().div_ceil(2)];
+            let amt = self.inner.read(&mut raw)?;
+            let mut out = vec![];
+            encode(&raw[..amt], &mut out);
+            self.out.extend(out);
+        }
+        Ok(self.drain(buf))
+    }
+}
+
+// writes netascii to inner as local text. a CR ending the data is written as is on flush
+pub struct Decoder<W> {
+    inner: W,
+    cr   : bool,
+    out  : Vec<u8>,
+}
+
+impl<W> Decoder<W> {
+    pub fn new(inner: W) -> Self {
+        Decoder { inner, cr: false, out: vec![] }
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        decode(&mut self.cr, buf, &mut self.out);
+        let rst = self.inner.write_all(&self.out);
+        self.out.clear();
+        rst.map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if std::mem::take(&mut self.cr) {
+            self.inner.write_all(b"\r")?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: crate::tftp::backend::TftpWriter> crate::tftp::backend::TftpWriter for Decoder<W> {
+    fn commit(&mut self) -> Result<(), std::io::Error> {
+        std::io::Write::flush(self)?;
+        self.inner.commit()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for Encoder<R> {
+    fn poll_read(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.out.is_empty() && buf.remaining() > 0 {
+            let mut raw = vec![0u8;buf.remaining().div_ceil(2)];
+            let mut raw = tokio::io::ReadBuf::new(&mut raw);
+            std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_read(cx, &mut raw))?;
+            let mut out = vec![];
+            encode(raw.filled(), &mut out);
+            this.out.extend(out);
+        }
+        let len = this.drain(buf.initialize_unfilled());
+        buf.advance(len);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<W: tokio::io::AsyncWrite + Unpin> Decoder<W> {
+    // write out what was decoded so far
+    fn poll_out(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        while !self.out.is_empty() {
+            let amt = std::task::ready!(std::pin::Pin::new(&mut self.inner).poll_write(cx, &self.out))?;
+            if amt == 0 {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.out.drain(..amt);
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for Decoder<W> {
+    fn poll_write(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        std::task::ready!(this.poll_out(cx))?;
+        decode(&mut this.cr, buf, &mut this.out);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if std::mem::take(&mut this.cr) {
+            this.out.push(b'\r');
+        }
+        std::task::ready!(this.poll_out(cx))?;
+        std::pin::Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        std::task::ready!(self.as_mut().poll_flush(cx))?;
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_mode() {
+    assert_eq!(Mode::from_name("NetASCII"), Some(Mode::Netascii));
+    assert_eq!(Mode::from_name("octet"), Some(Mode::Octet));
+    assert_eq!(Mode::from_name("mail"), None);
+    let file = std::env::temp_dir().join(format!("tftp.mode.{}", std::process::id()));
+    std::fs::write(&file, b"a\nb\rc").unwrap();
+    assert_eq!(Mode::Netascii.size(&file).unwrap(), 7);
+    assert_eq!(Mode::Octet.size(&file).unwrap(), 5);
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn test_netascii() {
+    use std::io::Write;
+    let text = b"line\nbare\rcr\r\r\n\n\rend\r".to_vec();
+    let wire = b"line\r\nbare\r\0cr\r\0\r\0\r\n\r\n\r\0end\r\0".to_vec();
+    // every read size splits some sequence across chunks
+    for len in 1..8 {
+        let mut src = Encoder::new(text.as_slice());
+        let mut out = vec![];
+        let mut buf = vec![0u8;len];
+        loop {
+            match src.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(out, wire);
+        let mut dst = Decoder::new(vec![]);
+        for chunk in wire.chunks(len) {
+            dst.write_all(chunk).unwrap();
+        }
+        dst.flush().unwrap();
+        assert_eq!(dst.inner, text);
+    }
+    // a CR not followed by LF or NUL is kept
+    let mut dst = Decoder::new(vec![]);
+    dst.write_all(b"a\rb\r").unwrap();
+    dst.flush().unwrap();
+    assert_eq!(dst.inner, b"a\rb\r");
+}
@@ -0,0 +1,206 @@
+/*++ @file
+
+    Copyright ©2024-2024 Liu Yi, efikarl@yeah.net
+
+    This program is just made available under the terms and conditions of the
+    MIT license: http://www.efikarl.com/mit-license.html
+
+    THE PROGRAM IS DISTRIBUTED UNDER THE MIT LICENSE ON AN "AS IS" BASIS,
+    WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
+--*/
+
+use crate::tftp::packet::*;
+
+pub const TFTP_OPTION_BLKSIZE   : & str =                "blksize";
+pub const TFTP_OPTION_TIMEOUT   : & str =                "timeout";
+pub const TFTP_OPTION_TSIZE     : & str =                  "tsize";
+pub const TFTP_OPTION_WINDOWSIZE: & str =             "windowsize";
+pub const TFTP_TIMEOUT_MIN      :   u64 =                        1;
+pub const TFTP_TIMEOUT_MAX      :   u64 =                      255;
+pub const TFTP_WINDOWSIZE_MAX   :   u16 =                       64;
+
+// values of the options (rfc2347) negotiated for a single transfer
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Options {
+    pub blksize   : usize,
+    pub timeout   : u64,
+    pub tsize     : Option<u64>,
+    pub windowsize: u16,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { blksize: TFTP_SIZE_DATA_BLOCK, timeout: TFTP_TIMEOUT, tsize: None, windowsize: 1 }
+    }
+}
+
+pub fn option<'a>(opts: &'a PacketOptions, name: &str) -> Option<&'a str> {
+    opts.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+}
+
+// client: options to request, with tsize being the size to be sent (wrq) or 0 (rrq). tsize is not
+// requested at all if the size is unknown
+pub fn request(opts: &PacketOptions, tsize: Option<u64>) -> PacketOptions {
+    let mut opts = opts.clone();
+    opts.retain(|(name, _)| tsize.is_some() || !name.eq_ignore_ascii_case(TFTP_OPTION_TSIZE));
+    for (name, value) in opts.iter_mut() {
+        if name.eq_ignore_ascii_case(TFTP_OPTION_TSIZE) {
+            *value = tsize.unwrap_or_default().to_string();
+        }
+    }
+    opts
+}
+
+// server: take the requested options we support in request order, unknown or invalid ones are ignored.
+// tsize is answered with the size of the file to be read (rrq) and left out if it is unknown, the size
+// announced by the client is taken otherwise (wrq)
+pub fn negotiate(req: &PacketOptions, opcode: OpCode, tsize: Option<u64>) -> (Options, PacketOptions) {
+    negotiate_with(req, opcode, tsize, TFTP_TIMEOUT, TFTP_SIZE_BLOCK_MAX, TFTP_WINDOWSIZE_MAX)
+}
+
+// negotiate from a timeout other than the default one, granting blksize up to blkmax and windowsize up to winmax
+pub fn negotiate_with(req: &PacketOptions, opcode: OpCode, tsize: Option<u64>, timeout: u64, blkmax: usize, winmax: u16) -> (Options, PacketOptions) {
+    let mut opts = Options { timeout, ..Options::default() };
+    let mut oack = PacketOptions::new();
+    for (name, value) in req {
+        let name = name.to_lowercase();
+        match name.as_str() {
+            TFTP_OPTION_BLKSIZE => {
+                if let Ok(v) = value.parse::<usize>() {
+                    if v >= TFTP_SIZE_BLOCK_MIN {
+                        opts.blksize = v.min(blkmax);
+                        oack.push((name, opts.blksize.to_string()));
+                    }
+                }
+            },
+            TFTP_OPTION_TIMEOUT => {
+                if let Ok(v) = value.parse::<u64>() {
+                    if (TFTP_TIMEOUT_MIN..=TFTP_TIMEOUT_MAX).contains(&v) {
+                        opts.timeout = v;
+                        oack.push((name, v.to_string()));
+                    }
+                }
+            },
+            TFTP_OPTION_TSIZE => {
+                if let Ok(v) = value.parse::<u64>() {
+                    let size = match opcode {
+                        OpCode::Wrq => Some(v),
+                        _           => tsize,
+                    };
+                    if let Some(size) = size {
+                        opts.tsize = Some(size);
+                        oack.push((name, size.to_string()));
+                    }
+                }
+            },
+            TFTP_OPTION_WINDOWSIZE => {
+                if let Ok(v) = value.parse::<u16>() {
+                    if v >= 1 {
+                        opts.windowsize = v.min(winmax);
+                        oack.push((name, opts.windowsize.to_string()));
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    (opts, oack)
+}
+
+// client: the oack may only carry options that have been requested, with acceptable values
+pub fn accept(req: &PacketOptions, oack: &PacketOptions) -> Result<Options, std::io::Error> {
+    let mut opts = Options::default();
+    for (name, value) in oack {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("EOR($): option({}) = {}", name, value));
+        let asked = option(req, name).ok_or_else(invalid)?;
+        match name.to_lowercase().as_str() {
+            TFTP_OPTION_BLKSIZE => {
+                let v = value.parse::<usize>().map_err(|_| invalid())?;
+                let max = asked.parse::<usize>().map_err(|_| invalid())?;
+                if v < TFTP_SIZE_BLOCK_MIN || v > max {
+                    return Err(invalid());
+                }
+                opts.blksize = v;
+            },
+            TFTP_OPTION_TIMEOUT => {
+                if value != asked {
+                    return Err(invalid());
+                }
+                opts.timeout = value.parse::<u64>().map_err(|_| invalid())?;
+            },
+            TFTP_OPTION_TSIZE => {
+                opts.tsize = Some(value.parse::<u64>().map_err(|_| invalid())?);
+            },
+            TFTP_OPTION_WINDOWSIZE => {
+                let v = value.parse::<u16>().map_err(|_| invalid())?;
+                let max = asked.parse::<u16>().map_err(|_| invalid())?;
+                if v < 1 || v > max {
+                    return Err(invalid());
+                }
+                opts.windowsize = v;
+            },
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(opts)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_negotiate() {
+    let req = vec![(String::from("BLKSIZE"), String::from("65535")), (String::from("foo"), String::from("bar"))];
+    let (opts, oack) = negotiate(&req, OpCode::Rrq, None);
+    assert_eq!(opts.blksize, TFTP_SIZE_BLOCK_MAX);
+    assert_eq!(oack, vec![(String::from("blksize"), TFTP_SIZE_BLOCK_MAX.to_string())]);
+    let req = vec![(String::from("blksize"), String::from("7"))];
+    let (opts, oack) = negotiate(&req, OpCode::Rrq, None);
+    assert_eq!(opts, Options::default());
+    assert!(oack.is_empty());
+    let req = vec![(String::from("tsize"), String::from("0")), (String::from("timeout"), String::from("3"))];
+    let (opts, oack) = negotiate(&req, OpCode::Rrq, Some(1026));
+    assert_eq!((opts.tsize, opts.timeout), (Some(1026), 3));
+    assert_eq!(oack, vec![(String::from("tsize"), String::from("1026")), (String::from("timeout"), String::from("3"))]);
+    let req = vec![(String::from("tsize"), String::from("4096")), (String::from("timeout"), String::from("256"))];
+    let (opts, oack) = negotiate(&req, OpCode::Wrq, Some(1026));
+    assert_eq!((opts.tsize, opts.timeout), (Some(4096), TFTP_TIMEOUT));
+    assert_eq!(oack, vec![(String::from("tsize"), String::from("4096"))]);
+    let (opts, oack) = negotiate(&req, OpCode::Rrq, None);
+    assert_eq!((opts.tsize, opts.timeout), (None, TFTP_TIMEOUT));
+    assert!(oack.is_empty());
+    let req = vec![(String::from("windowsize"), String::from("16")), (String::from("windowsize"), String::from("0"))];
+    let (opts, oack) = negotiate(&req, OpCode::Rrq, None);
+    assert_eq!(opts.windowsize, 16);
+    assert_eq!(oack, vec![(String::from("windowsize"), String::from("16"))]);
+    let req = vec![(String::from("blksize"), String::from("1468"))];
+    let (opts, oack) = negotiate_with(&req, OpCode::Rrq, None, 2, 1024, TFTP_WINDOWSIZE_MAX);
+    assert_eq!((opts.blksize, opts.timeout), (1024, 2));
+    assert_eq!(oack, vec![(String::from("blksize"), String::from("1024"))]);
+    let req = vec![(String::from("windowsize"), String::from("65535"))];
+    let (opts, oack) = negotiate(&req, OpCode::Rrq, None);
+    assert_eq!(opts.windowsize, TFTP_WINDOWSIZE_MAX);
+    assert_eq!(oack, vec![(String::from("windowsize"), TFTP_WINDOWSIZE_MAX.to_string())]);
+}
+
+#[test]
+fn test_request() {
+    let opts = vec![(String::from("tsize"), String::from("0")), (String::from("blksize"), String::from("1428"))];
+    assert_eq!(request(&opts, Some(1026)), vec![(String::from("tsize"), String::from("1026")), (String::from("blksize"), String::from("1428"))]);
+    assert_eq!(request(&opts, None), vec![(String::from("blksize"), String::from("1428"))]);
+}
+
+#[test]
+fn test_accept() {
+    let req = vec![(String::from("blksize"), String::from("1428"))];
+    let oack = vec![(String::from("blksize"), String::from("1024"))];
+    assert_eq!(accept(&req, &oack).unwrap().blksize, 1024);
+    let oack = vec![(String::from("blksize"), String::from("2048"))];
+    assert!(accept(&req, &oack).is_err());
+    let oack = vec![(String::from("tsize"), String::from("0"))];
+    assert!(accept(&req, &oack).is_err());
+    let req = vec![(String::from("tsize"), String::from("0")), (String::from("timeout"), String::from("3"))];
+    let oack = vec![(String::from("tsize"), String::from("1026")), (String::from("timeout"), String::from("3"))];
+    assert_eq!(accept(&req, &oack).unwrap().tsize, Some(1026));
+    let oack = vec![(String::from("timeout"), String::from("5"))];
+    assert!(accept(&req, &oack).is_err());
+}
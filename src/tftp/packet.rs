@@ -14,19 +14,22 @@ pub const TFTP_PORT             :   u16 =                   0x0045;
 pub const TFTP_TID0             :   u16 =                   0x0000;
 pub const TFTP_MODE             : & str =                  "octet";
 pub const TFTP_SIZE_DATA_BLOCK  : usize =                   0x0200;
+pub const TFTP_SIZE_BLOCK_MIN   : usize =                   0x0008;
+pub const TFTP_SIZE_BLOCK_MAX   : usize =                   0xffb8;
 pub const TFTP_SIZE_PACKET_MAX  : usize = TFTP_SIZE_DATA_BLOCK + 4;
 
 ///////////////////////////////////////////////////////////////////////////////
 use crate::file::extend::*;
+use crate::tftp::error::ErrorCode;
 ///////////////////////////////////////////////////////////////////////////////
 
 trait TftpTypeIntoRaw<T> {
-    fn into_raw(&self) -> Vec<u8>;
+    fn to_raw(&self) -> Vec<u8>;
     fn from_raw(i: &[u8]) -> Self;
 }
 
 impl TftpTypeIntoRaw<String> for String {
-    fn into_raw(&self) -> Vec<u8> {
+    fn to_raw(&self) -> Vec<u8> {
         self.as_bytes().to_vec()
     }
     fn from_raw(i: &[u8]) -> Self {
@@ -35,11 +38,42 @@ impl TftpTypeIntoRaw<String> for String {
 }
 
 impl TftpTypeIntoRaw<u16> for u16 {
-    fn into_raw(&self) -> Vec<u8> {
+    fn to_raw(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
     fn from_raw(i: &[u8]) -> Self {
-        u16::from_be_bytes(i.try_into().unwrap())
+        u16::from_be_bytes([i[0], i[1]])
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketError {
+    Truncated           (usize),
+    UnknownOpCode       (u16  ),
+    MissingTerminator   (OpCode),
+    OversizedBlock      (usize),
+    InvalidFileName     (Vec<u8>),
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketError::Truncated(len)             => write!(f, "truncated packet: {} bytes", len),
+            PacketError::UnknownOpCode(code)        => write!(f, "unknown opcode: {}", code),
+            PacketError::MissingTerminator(opcode)  => write!(f, "missing terminator: {:?}", opcode),
+            PacketError::OversizedBlock(len)        => write!(f, "oversized block: {} bytes", len),
+            PacketError::InvalidFileName(_)         => write!(f, "invalid file name: not utf-8"),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+impl From<PacketError> for std::io::Error {
+    fn from(e: PacketError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
     }
 }
 
@@ -53,25 +87,31 @@ pub enum OpCode {
     Dat = 0x03,
     Ack = 0x04,
     Err = 0x05,
+    Oack = 0x06,
 }
 
-impl From<&[u8]> for OpCode {
-    fn from(i: &[u8]) -> Self {
-        assert!(i.len() == 2);
-        let o = u16::from_be_bytes([i[0], i[1]]);
-        assert!((o >=1) && (o <= 5));
-        unsafe {
-            std::mem::transmute::<u16, OpCode>(o)
+impl TryFrom<&[u8]> for OpCode {
+    type Error = PacketError;
+
+    fn try_from(i: &[u8]) -> Result<Self, Self::Error> {
+        if i.len() != 2 {
+            return Err(PacketError::Truncated(i.len()));
+        }
+        match u16::from_be_bytes([i[0], i[1]]) {
+            0x01 => Ok(OpCode::Rrq),
+            0x02 => Ok(OpCode::Wrq),
+            0x03 => Ok(OpCode::Dat),
+            0x04 => Ok(OpCode::Ack),
+            0x05 => Ok(OpCode::Err),
+            0x06 => Ok(OpCode::Oack),
+            o    => Err(PacketError::UnknownOpCode(o)),
         }
     }
 }
 
 impl From<&OpCode> for Vec::<u8> {
     fn from(i: &OpCode) -> Self {
-        let o = unsafe {
-            std::mem::transmute::<OpCode, u16>(*i)
-        };
-        Vec::from(o.to_be_bytes())
+        Vec::from((*i as u16).to_be_bytes())
     }
 }
 
@@ -83,14 +123,24 @@ type PacketBlockId    = u16;
 type PacketData       = Vec<u8>;
 type PacketErrCode    = u16;
 type PacketErrMsgs    = String;
+pub type PacketOptions = Vec<(String, String)>;
 
 #[derive(Debug, PartialEq)]
 pub enum Packet {
-    Rrq (PacketFileName, PacketOpMode ),
-    Wrq (PacketFileName, PacketOpMode ),
+    Rrq (PacketFileName, PacketOpMode , PacketOptions),
+    Wrq (PacketFileName, PacketOpMode , PacketOptions),
     Dat (PacketBlockId , PacketData   ),
     Ack (PacketBlockId                ),
     Err (PacketErrCode , PacketErrMsgs),
+    Oack(PacketOptions                ),
+}
+
+// split a NUL terminated string off the head of raw: (string, rest)
+fn split_cstr(raw: &[u8], opcode: OpCode) -> Result<(&[u8], &[u8]), PacketError> {
+    match raw.iter().position(|&p| p == 0) {
+        Some(e) => Ok((&raw[..e], &raw[e + 1..])),
+        None    => Err(PacketError::MissingTerminator(opcode)),
+    }
 }
 
 impl Packet {
@@ -101,20 +151,30 @@ impl Packet {
             Packet::Dat (..)  => OpCode::Dat,
             Packet::Ack (..)  => OpCode::Ack,
             Packet::Err (..)  => OpCode::Err,
+            Packet::Oack(..)  => OpCode::Oack,
         }
     }
 
-    pub fn newrrq<F: PathEx, M: ToString>(file: F, mode: M) -> Packet {
-        if mode.to_string().to_lowercase() != "octet" {
-            panic!("N/A");
+    pub fn options(&self) -> Option<&PacketOptions> {
+        match self {
+            Packet::Rrq (_, _, opts) | Packet::Wrq(_, _, opts) | Packet::Oack(opts) => Some(opts),
+            _ => None,
         }
-        Packet::Rrq(file.to_string(), mode.to_string())
     }
-    pub fn newwrq<F: PathEx, M: ToString>(file: F, mode: M) -> Packet {
-        if mode.to_string().to_lowercase() != "octet" {
-            panic!("N/A");
+
+    pub fn with_options(mut self, options: PacketOptions) -> Packet {
+        match &mut self {
+            Packet::Rrq (_, _, opts) | Packet::Wrq(_, _, opts) | Packet::Oack(opts) => *opts = options,
+            _ => {},
         }
-        Packet::Wrq(file.to_string(), mode.to_string())
+        self
+    }
+
+    pub fn newrrq<F: PathEx, M: ToString>(file: F, mode: M) -> Packet {
+        Packet::Rrq(file.to_string(), mode.to_string(), PacketOptions::new())
+    }
+    pub fn newwrq<F: PathEx, M: ToString>(file: F, mode: M) -> Packet {
+        Packet::Wrq(file.to_string(), mode.to_string(), PacketOptions::new())
     }
     pub fn newdat(blkid: u16, data: PacketData) -> Packet {
         Packet::Dat(blkid, data)
@@ -122,82 +182,243 @@ impl Packet {
     pub fn newack(blkid: u16) -> Packet {
         Packet::Ack(blkid)
     }
-    pub fn newerr<T: ToString>(code: u16, msgs: T) -> Packet {
-        Packet::Err(code, msgs.to_string())
+    pub fn newerr<T: ToString>(code: ErrorCode, msgs: T) -> Packet {
+        Packet::Err(code.into(), msgs.to_string())
+    }
+    pub fn newoack(opts: PacketOptions) -> Packet {
+        Packet::Oack(opts)
     }
 
     pub fn encode(&self) -> Vec<u8> {
         let mut v: Vec<u8> = Vec::new();
+        self.write(&mut v);
+        v
+    }
 
-        v.append(&mut Vec::<u8>::from(&self.opcode()));
+    // encode into buf and return its length. panics if buf cannot hold the packet
+    pub fn encode_into(&self, buf: &mut [u8]) -> usize {
+        let mut sink = Slice(buf, 0);
+        self.write(&mut sink);
+        sink.1
+    }
+
+    fn write<S: Sink>(&self, v: &mut S) {
+        v.put(&(self.opcode() as u16).to_be_bytes());
         match self {
-            Packet::Rrq(file, mode) | Packet::Wrq(file, mode) => {
-                v.extend_from_slice(&file.as_bytes());
-                v.push(0);
-                v.extend_from_slice(&mode.as_bytes());
-                v.push(0);
+            Packet::Rrq(file, mode, opts) | Packet::Wrq(file, mode, opts) => {
+                v.cstr(file.as_bytes());
+                v.cstr(mode.as_bytes());
+                for (name, value) in opts {
+                    v.cstr(name.as_bytes());
+                    v.cstr(value.as_bytes());
+                }
             },
             Packet::Dat(blkid, data)  => {
-                assert!(data.len() <= TFTP_SIZE_DATA_BLOCK);
-                v.extend_from_slice(&blkid.into_raw());
-                v.extend_from_slice(&data);
+                assert!(data.len() <= TFTP_SIZE_BLOCK_MAX);
+                v.put(&blkid.to_raw());
+                v.put(data);
             },
             Packet::Ack(blkid)  => {
-                v.extend_from_slice(&blkid.into_raw());
+                v.put(&blkid.to_raw());
             },
             Packet::Err (code, msgs)  => {
-                v.extend_from_slice(&code.into_raw());
-                v.extend_from_slice(&msgs.as_bytes());
-                v.push(0);
+                v.put(&code.to_raw());
+                v.cstr(msgs.as_bytes());
             },
+            Packet::Oack(opts)  => {
+                for (name, value) in opts {
+                    v.cstr(name.as_bytes());
+                    v.cstr(value.as_bytes());
+                }
+            },
+        }
+    }
+
+    pub fn try_decode(raw: &[u8]) -> Result<Self, PacketError> {
+        PacketRef::try_decode(raw).map(Packet::from)
+    }
+}
+
+// where an encoded packet goes, a growing vec or a buffer of fixed size
+trait Sink {
+    fn put(&mut self, raw: &[u8]);
+    fn cstr(&mut self, raw: &[u8]) {
+        self.put(raw);
+        self.put(&[0]);
+    }
+}
+
+impl Sink for Vec<u8> {
+    fn put(&mut self, raw: &[u8]) {
+        self.extend_from_slice(raw);
+    }
+}
+
+struct Slice<'b>(&'b mut [u8], usize);
+
+impl Sink for Slice<'_> {
+    fn put(&mut self, raw: &[u8]) {
+        self.0[self.1..self.1 + raw.len()].copy_from_slice(raw);
+        self.1 += raw.len();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// the name/value option pairs of a packet, as they are on the wire
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct OptionsRef<'a>(&'a [u8]);
+
+impl<'a> OptionsRef<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+        let mut cstr = self.0.split(|&p| p == 0);
+        std::iter::from_fn(move || Some((cstr.next()?, cstr.next()?)))
+    }
+}
+
+impl From<OptionsRef<'_>> for PacketOptions {
+    fn from(opts: OptionsRef<'_>) -> Self {
+        opts.iter().map(|(name, value)| (String::from_raw(name), String::from_raw(value))).collect()
+    }
+}
+
+// a packet borrowing from the buffer it was received in, without any allocation
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PacketRef<'a> {
+    Rrq (&'a str, &'a [u8], OptionsRef<'a>),
+    Wrq (&'a str, &'a [u8], OptionsRef<'a>),
+    Dat (PacketBlockId, &'a [u8]),
+    Ack (PacketBlockId),
+    Err (PacketErrCode, &'a [u8]),
+    Oack(OptionsRef<'a>),
+}
+
+// check the trailing name/value option pairs are all terminated
+fn check_opts(raw: &[u8], opcode: OpCode) -> Result<OptionsRef<'_>, PacketError> {
+    let mut rest = raw;
+    while !rest.is_empty() {
+        let (_, next) = split_cstr(rest, opcode)?;
+        let (_, next) = split_cstr(next, opcode)?;
+        rest = next;
+    }
+    Ok(OptionsRef(raw))
+}
+
+impl<'a> PacketRef<'a> {
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            PacketRef::Rrq (..)  => OpCode::Rrq,
+            PacketRef::Wrq (..)  => OpCode::Wrq,
+            PacketRef::Dat (..)  => OpCode::Dat,
+            PacketRef::Ack (..)  => OpCode::Ack,
+            PacketRef::Err (..)  => OpCode::Err,
+            PacketRef::Oack(..)  => OpCode::Oack,
         }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v: Vec<u8> = Vec::new();
+        self.write(&mut v);
         v
     }
 
-    pub fn decode(raw: &Vec<u8>, len: usize) -> Self {
-        assert!((len == raw.len()) && (len >= std::mem::size_of::<OpCode>() + 2) && (len <= TFTP_SIZE_PACKET_MAX));
+    // encode into buf and return its length. panics if buf cannot hold the packet
+    pub fn encode_into(&self, buf: &mut [u8]) -> usize {
+        let mut sink = Slice(buf, 0);
+        self.write(&mut sink);
+        sink.1
+    }
 
-        let opcode = OpCode::from(&raw[0..2]);
+    fn write<S: Sink>(&self, v: &mut S) {
+        v.put(&(self.opcode() as u16).to_be_bytes());
+        match self {
+            PacketRef::Rrq(file, mode, opts) | PacketRef::Wrq(file, mode, opts) => {
+                v.cstr(file.as_bytes());
+                v.cstr(mode);
+                v.put(opts.0);
+            },
+            PacketRef::Dat(blkid, data)  => {
+                assert!(data.len() <= TFTP_SIZE_BLOCK_MAX);
+                v.put(&blkid.to_raw());
+                v.put(data);
+            },
+            PacketRef::Ack(blkid)  => {
+                v.put(&blkid.to_raw());
+            },
+            PacketRef::Err (code, msgs)  => {
+                v.put(&code.to_raw());
+                v.cstr(msgs);
+            },
+            PacketRef::Oack(opts)  => {
+                v.put(opts.0);
+            },
+        }
+    }
+
+    pub fn try_decode(raw: &'a [u8]) -> Result<Self, PacketError> {
+        if raw.len() < std::mem::size_of::<OpCode>() + 2 {
+            return Err(PacketError::Truncated(raw.len()));
+        }
+
+        let opcode = OpCode::try_from(&raw[0..2])?;
         match opcode {
             OpCode::Rrq | OpCode::Wrq => {
-                let s = 1 + 1;
-                let e = raw[s..].iter().position(|&p| p == 0).unwrap();
-                let e = s + e;
-                let file = String::from_utf8_lossy(&raw[s..e]).to_string();
-                let s = e + 1;
-                let e = raw[s..].iter().position(|&p| p == 0).unwrap();
-                let e = s + e;
-                let mode = String::from_utf8_lossy(&raw[s..e]).to_string();
+                let (file, rest) = split_cstr(&raw[2..], opcode)?;
+                let file = std::str::from_utf8(file).map_err(|_| PacketError::InvalidFileName(file.to_vec()))?;
+                let (mode, rest) = split_cstr(rest, opcode)?;
+                let opts = check_opts(rest, opcode)?;
                 if opcode == OpCode::Rrq {
-                    return Packet::Rrq(file, mode);
+                    Ok(PacketRef::Rrq(file, mode, opts))
                 } else {
-                    return Packet::Wrq(file, mode);
+                    Ok(PacketRef::Wrq(file, mode, opts))
                 }
             },
             OpCode::Dat => {
                 let blkid = u16::from_raw(&raw[2..4]);
-                let data = raw[4..len].to_vec();
-                return Packet::Dat(blkid, data);
+                let data = &raw[4..];
+                if data.len() > TFTP_SIZE_BLOCK_MAX {
+                    return Err(PacketError::OversizedBlock(data.len()));
+                }
+                Ok(PacketRef::Dat(blkid, data))
             },
             OpCode::Ack => {
                 let blkid = u16::from_raw(&raw[2..4]);
-                return Packet::Ack(blkid);
+                Ok(PacketRef::Ack(blkid))
             },
             OpCode::Err => {
                 let code = u16::from_raw(&raw[2..4]);
-                let s = 2 + 2;
-                let e = raw[s..].iter().position(|&p| p == 0).unwrap();
-                let e = s + e;
-                let msgs = String::from_utf8_lossy(&raw[s..e]).to_string();
-                return Packet::Err(code, msgs);
+                let (msgs, _) = split_cstr(&raw[4..], opcode)?;
+                Ok(PacketRef::Err(code, msgs))
+            },
+            OpCode::Oack => {
+                let opts = check_opts(&raw[2..], opcode)?;
+                Ok(PacketRef::Oack(opts))
             },
         }
     }
 }
 
+impl From<PacketRef<'_>> for Packet {
+    fn from(pkt: PacketRef<'_>) -> Self {
+        match pkt {
+            PacketRef::Rrq(file, mode, opts) => Packet::Rrq(file.to_string(), String::from_raw(mode), opts.into()),
+            PacketRef::Wrq(file, mode, opts) => Packet::Wrq(file.to_string(), String::from_raw(mode), opts.into()),
+            PacketRef::Dat(blkid, data)      => Packet::Dat(blkid, data.to_vec()),
+            PacketRef::Ack(blkid)            => Packet::Ack(blkid),
+            PacketRef::Err(code, msgs)       => Packet::Err(code, String::from_raw(msgs)),
+            PacketRef::Oack(opts)            => Packet::Oack(opts.into()),
+        }
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
 #[test]
+#[allow(clippy::char_lit_as_u8)]
 fn test_encode() {
     let packet1 = Packet::newrrq("azAZ09-0.txt", "octet");
     let v0: Vec<u8> = vec![0, 1,
@@ -220,7 +441,7 @@ fn test_encode() {
     let v0: Vec<u8> = vec![0, 4, 0, 0x10 ];
     let v4 = packet4.encode();
     assert_eq!(v0, v4);
-    let packet5 = Packet::newerr(1, &String::from("error"));
+    let packet5 = Packet::newerr(ErrorCode::FileNotFound, String::from("error"));
     let v0: Vec<u8> = vec![0, 5, 0, 0x01,
         'e' as u8, 'r' as u8, 'r' as u8, 'o' as u8, 'r' as u8, 0 ];
     let v5 = packet5.encode();
@@ -228,31 +449,94 @@ fn test_encode() {
 }
 
 #[test]
+#[allow(clippy::char_lit_as_u8)]
 fn test_decode() {
     let packet1 = Packet::newrrq("azAZ09-0.txt", "octet");
     let v0: Vec<u8> = vec![0, 1,
         'a' as u8, 'z' as u8, 'A' as u8, 'Z' as u8, '0' as u8, '9' as u8, '-' as u8, '0' as u8, '.' as u8, 't' as u8, 'x' as u8, 't' as u8, 0,
         'o' as u8, 'c' as u8, 't' as u8, 'e' as u8, 't' as u8, 0 ];
-        let packet0 = Packet::decode(&v0, v0.len());
+        let packet0 = Packet::try_decode(&v0).unwrap();
     assert_eq!(packet0, packet1);
     let packet2 = Packet::newwrq("azAZ09-0.txt", "OCTET");
     let v0: Vec<u8> = vec![0, 2,
         'a' as u8, 'z' as u8, 'A' as u8, 'Z' as u8, '0' as u8, '9' as u8, '-' as u8, '0' as u8, '.' as u8, 't' as u8, 'x' as u8, 't' as u8, 0,
         'O' as u8, 'C' as u8, 'T' as u8, 'E' as u8, 'T' as u8, 0 ];
-        let packet0 = Packet::decode(&v0, v0.len());
+        let packet0 = Packet::try_decode(&v0).unwrap();
     assert_eq!(packet0, packet2);
     let packet3 = Packet::newdat(0x10, vec!['e' as u8, 'f' as u8, 'i' as u8, '\r' as u8, 0, 'k' as u8, 'a' as u8, '\n' as u8 ]);
     let v0: Vec<u8> = vec![0, 3, 0, 0x10,
         'e' as u8, 'f' as u8, 'i' as u8, '\r' as u8, 0, 'k' as u8, 'a' as u8, '\n' as u8 ];
-    let packet0 = Packet::decode(&v0, v0.len());
+    let packet0 = Packet::try_decode(&v0).unwrap();
     assert_eq!(packet0, packet3);
     let packet4 = Packet::newack(0x10);
     let v0: Vec<u8> = vec![0, 4, 0, 0x10 ];
-    let packet0 = Packet::decode(&v0, v0.len());
+    let packet0 = Packet::try_decode(&v0).unwrap();
     assert_eq!(packet0, packet4);
-    let packet5 = Packet::newerr(2, &String::from("error"));
+    let packet5 = Packet::newerr(ErrorCode::AccessViolation, String::from("error"));
     let v0: Vec<u8> = vec![0, 5, 0, 0x02,
         'e' as u8, 'r' as u8, 'r' as u8, 'o' as u8, 'r' as u8, 0 ];
-    let packet0 = Packet::decode(&v0, v0.len());
+    let packet0 = Packet::try_decode(&v0).unwrap();
     assert_eq!(packet0, packet5);
 }
+
+#[test]
+fn test_try_decode() {
+    assert_eq!(Packet::try_decode(&[0, 1, 0]), Err(PacketError::Truncated(3)));
+    assert_eq!(Packet::try_decode(&[0, 9, 0, 1]), Err(PacketError::UnknownOpCode(9)));
+    assert_eq!(Packet::try_decode(&[0, 1, b'a', 0, b'o', b'c']), Err(PacketError::MissingTerminator(OpCode::Rrq)));
+    assert_eq!(Packet::try_decode(&[0, 2, b'a', b'b']), Err(PacketError::MissingTerminator(OpCode::Wrq)));
+    assert_eq!(Packet::try_decode(&[0, 5, 0, 1, b'e']), Err(PacketError::MissingTerminator(OpCode::Err)));
+    assert_eq!(Packet::try_decode(&[0, 1, 0xff, 0xfe, 0, b'o', 0]), Err(PacketError::InvalidFileName(vec![0xff, 0xfe])));
+    let mut v0 = vec![0, 3, 0, 1];
+    v0.extend_from_slice(&[0u8; TFTP_SIZE_BLOCK_MAX + 1]);
+    assert_eq!(Packet::try_decode(&v0), Err(PacketError::OversizedBlock(TFTP_SIZE_BLOCK_MAX + 1)));
+    assert_eq!(Packet::try_decode(&[0, 4, 0, 1]), Ok(Packet::newack(1)));
+}
+
+#[test]
+fn test_options() {
+    let opts = vec![(String::from("blksize"), String::from("1428")), (String::from("tsize"), String::from("0"))];
+    let packet1 = Packet::newrrq("a", "octet").with_options(opts.clone());
+    let v0: Vec<u8> = vec![0, 1, b'a', 0, b'o', b'c', b't', b'e', b't', 0,
+        b'b', b'l', b'k', b's', b'i', b'z', b'e', 0, b'1', b'4', b'2', b'8', 0,
+        b't', b's', b'i', b'z', b'e', 0, b'0', 0 ];
+    assert_eq!(v0, packet1.encode());
+    assert_eq!(Packet::try_decode(&v0), Ok(packet1));
+    let packet2 = Packet::newoack(opts.clone());
+    let v0: Vec<u8> = vec![0, 6,
+        b'b', b'l', b'k', b's', b'i', b'z', b'e', 0, b'1', b'4', b'2', b'8', 0,
+        b't', b's', b'i', b'z', b'e', 0, b'0', 0 ];
+    assert_eq!(v0, packet2.encode());
+    assert_eq!(Packet::try_decode(&v0), Ok(packet2));
+    assert_eq!(Packet::try_decode(&v0[..v0.len() - 3]), Err(PacketError::MissingTerminator(OpCode::Oack)));
+}
+
+#[test]
+fn test_packet_ref() {
+    let opts = vec![(String::from("blksize"), String::from("1428")), (String::from("tsize"), String::from("0"))];
+    let mut buf = [0u8;TFTP_SIZE_PACKET_MAX];
+    for packet in [
+        Packet::newrrq("a", "octet").with_options(opts.clone()),
+        Packet::newwrq("b", "netascii"),
+        Packet::newdat(7, vec![1, 2, 3]),
+        Packet::newack(7),
+        Packet::newerr(ErrorCode::FileNotFound, "none"),
+        Packet::newoack(opts.clone()),
+    ] {
+        let v0 = packet.encode();
+        let pkt = PacketRef::try_decode(&v0).unwrap();
+        assert_eq!(pkt.opcode(), packet.opcode());
+        assert_eq!(pkt.encode(), v0);
+        let len = pkt.encode_into(&mut buf);
+        assert_eq!(&buf[..len], &v0[..]);
+        let len = packet.encode_into(&mut buf);
+        assert_eq!(&buf[..len], &v0[..]);
+        assert_eq!(Packet::from(pkt), packet);
+    }
+    let v0 = Packet::newdat(1, vec![9;4]).encode();
+    assert!(matches!(PacketRef::try_decode(&v0), Ok(PacketRef::Dat(1, data)) if std::ptr::eq(data, &v0[4..])));
+    let v0 = Packet::newoack(opts).encode();
+    let Ok(PacketRef::Oack(oack)) = PacketRef::try_decode(&v0) else { panic!() };
+    assert_eq!(oack.iter().collect::<Vec<_>>(), vec![(&b"blksize"[..], &b"1428"[..]), (&b"tsize"[..], &b"0"[..])]);
+    assert_eq!(PacketRef::try_decode(&[0, 6, b'a', 0, b'b']), Err(PacketError::MissingTerminator(OpCode::Oack)));
+}
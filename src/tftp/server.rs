@@ -9,148 +9,787 @@
     WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
 --*/
 
-use crate::file::extend::*;
 use crate::tftp::packet::*;
+use crate::tftp::option::*;
+use crate::tftp::transfer::*;
+use crate::tftp::error::*;
+use crate::tftp::netascii::*;
+use crate::tftp::backend::*;
 
-pub struct Server(bool);
+pub const TFTP_SESSIONS         : usize =                       64;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum WriteMode {
+    // no wrq is accepted
+    ReadOnly,
+    // wrq of an existing file is refused with error 6
+    NewOnly,
+    #[default]
+    Overwrite,
+}
+
+// what a wrq may write: the write mode and, if any, the directories it is limited to
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Policy {
+    pub write: WriteMode,
+    pub allow: Vec<std::path::PathBuf>,
+}
+
+impl Policy {
+    pub fn readonly() -> Self {
+        Policy { write: WriteMode::ReadOnly, allow: vec![] }
+    }
+
+    pub fn write(mut self, write: WriteMode) -> Self {
+        self.write = write;
+        self
+    }
+
+    // a directory wrqs may write below, relative to the root of the backend as requested files are
+    pub fn allow<P: AsRef<std::path::Path>>(mut self, dir: P) -> Result<Self, std::io::Error> {
+        let dir = dir.as_ref();
+        if !dir.components().all(|item| matches!(item, std::path::Component::Normal(_) | std::path::Component::CurDir)) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("EOR($): allow {}: not below root", dir.display())));
+        }
+        self.allow.push(dir.components().filter(|item| *item != std::path::Component::CurDir).collect());
+        Ok(self)
+    }
+
+    // check a wrq of file
+    pub fn writable(&self, backend: &dyn TftpBackend, file: &str) -> Result<(), std::io::Error> {
+        if self.write == WriteMode::ReadOnly {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "EOR($): read only"));
+        }
+        if !self.allow.is_empty() {
+            // checked where the file and the directories resolve to, so that no link leads out of them
+            let file = backend.resolve(file)?;
+            if !self.allow.iter().any(|dir| backend.resolve(&dir.to_string_lossy()).is_ok_and(|dir| file.starts_with(dir))) {
+                return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "EOR($): write not allowed"));
+            }
+        }
+        if self.write == WriteMode::NewOnly && backend.exists(file) {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "EOR($): file already exists"));
+        }
+        Ok(())
+    }
+}
+
+// what a handler makes of a rrq
+#[derive(Debug, Clone, PartialEq)]
+pub enum Answer {
+    // serve these bytes, generated for the client
+    Data(Vec<u8>),
+    // serve this file of the backend instead
+    Redirect(String),
+    // refuse with this error code and message
+    Deny(ErrorCode, String),
+    // serve the file requested, unless a later handler answers otherwise
+    Pass,
+}
+
+// a handler gets the file requested by a client, the client and the options it negotiates
+pub type Handler = Box<dyn Fn(&str, std::net::SocketAddr, &Options) -> Answer + Send + Sync>;
+
+// what a request comes to: a file to send or to recv with the options negotiated for it, or the error
+// refusing it
+pub(crate) enum Session<R, W> {
+    Send(R, Options, PacketOptions),
+    Recv(W, Options, PacketOptions),
+    Deny(ErrorCode, String),
+}
+
+impl<R, W> Session<R, W> {
+    // the same session, reading and writing its file through src and dst
+    pub(crate) fn map<A, B>(self, src: impl FnOnce(R) -> A, dst: impl FnOnce(W) -> B) -> Session<A, B> {
+        match self {
+            Session::Send(r, opts, oack) => Session::Send(src(r), opts, oack),
+            Session::Recv(w, opts, oack) => Session::Recv(dst(w), opts, oack),
+            Session::Deny(code, msgs)    => Session::Deny(code, msgs),
+        }
+    }
+}
+
+// a reader with its size on the wire if known
+pub(crate) type Opened = (Box<dyn std::io::Read + Send>, Option<u64>);
+
+// a session in progress: the client tid and a hash of its request, rather than a copy of it
+type Key = (std::net::SocketAddr, u64);
+
+// the sessions in progress, shared by a listener and its sessions
+#[derive(Default, Clone)]
+pub(crate) struct Active(std::sync::Arc<std::sync::Mutex<std::collections::HashSet<Key>>>);
+
+// what a listener makes of a request
+pub(crate) enum Admit {
+    Slot(Slot),
+    // a retransmitted request of a session in progress
+    Again,
+    // sessions sessions in progress already
+    Busy,
+}
+
+impl Active {
+    pub(crate) fn admit(&self, clt: std::net::SocketAddr, req: &[u8], sessions: usize) -> Admit {
+        let mut hasher = std::hash::DefaultHasher::new();
+        std::hash::Hash::hash(req, &mut hasher);
+        let key = (clt, std::hash::Hasher::finish(&hasher));
+        let mut active = self.0.lock().unwrap();
+        if active.contains(&key) {
+            return Admit::Again;
+        }
+        if active.len() >= sessions {
+            return Admit::Busy;
+        }
+        active.insert(key);
+        Admit::Slot(Slot { active: self.clone(), key })
+    }
+}
+
+// the place of a session among those in progress, given back once dropped
+pub(crate) struct Slot {
+    active: Active,
+    key   : Key,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.active.0.lock().unwrap().remove(&self.key);
+    }
+}
+
+pub struct Server {
+    pub(crate) info    : bool,
+    pub(crate) quota   : Option<u64>,
+    pub(crate) retries : u32,
+    pub(crate) backoff : u32,
+    pub(crate) rollover: Rollover,
+    pub(crate) sessions: usize,
+    pub(crate) backend : Box<dyn TftpBackend>,
+    pub(crate) policy  : Policy,
+    pub(crate) handlers: Vec<Handler>,
+    pub(crate) addr    : std::net::SocketAddr,
+    pub(crate) timeout : u64,
+    pub(crate) blksize : usize,
+    pub(crate) windowsize: u16,
+    pub(crate) dualstack: Option<bool>,
+}
+
+// a server of fixed defaults, serving the current directory whatever the environment
+impl Default for Server {
+    fn default() -> Self {
+        let backend = Box::new(Directory::new("."));
+
+        Self { info: false, quota: None, retries: TFTP_RETRIES, backoff: TFTP_BACKOFF, rollover: Rollover::default(), sessions: TFTP_SESSIONS, backend, policy: Policy::default(), handlers: vec![],
+            addr: (std::net::Ipv4Addr::UNSPECIFIED, TFTP_PORT).into(), timeout: TFTP_TIMEOUT, blksize: TFTP_SIZE_BLOCK_MAX, windowsize: TFTP_WINDOWSIZE_MAX, dualstack: None }
+    }
+}
 
 impl Server {
+    // the defaults, but for TFTP_INFO, TFTP_QUOTA and TFTP_ROOT taken from the environment
     pub fn new() -> Self {
-        let  info = if let Some(_) = std::env::var_os("TFTP_INFO") { true } else { false };
+        let  info = std::env::var_os("TFTP_INFO").is_some();
+        let quota = std::env::var("TFTP_QUOTA").ok().and_then(|v| v.parse::<u64>().ok());
+        let mut server = Self { info, quota, ..Self::default() };
+        if let Some(root) = std::env::var_os("TFTP_ROOT") {
+            server.root(root);
+        }
+        server
+    }
+
+    // a server configured from the defaults, the environment left aside
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder { server: Server::default() }
+    }
+
+    pub fn retry(&mut self, retries: u32, backoff: u32) -> &mut Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn rollover(&mut self, rollover: Rollover) -> &mut Self {
+        self.rollover = rollover;
+        self
+    }
+
+    // at most sessions transfers are served at the same time
+    pub fn sessions(&mut self, sessions: usize) -> &mut Self {
+        self.sessions = std::cmp::max(sessions, 1);
+        self
+    }
+
+    // files are served from and written to below root only
+    pub fn root<P: AsRef<std::path::Path>>(&mut self, root: P) -> &mut Self {
+        self.backend(Directory::new(root))
+    }
+
+    pub fn backend<B: TftpBackend + 'static>(&mut self, backend: B) -> &mut Self {
+        self.backend = Box::new(backend);
+        self
+    }
+
+    pub fn policy(&mut self, policy: Policy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+
+    // answer rrqs in place of the backend, handlers being asked in the order they are added
+    pub fn handler<F: Fn(&str, std::net::SocketAddr, &Options) -> Answer + Send + Sync + 'static>(&mut self, handler: F) -> &mut Self {
+        self.handlers.push(Box::new(handler));
+        self
+    }
 
-        Self(info)
+    pub fn listen(&self) -> Result<(), std::io::Error> {
+        self.serve(bind(self.addr, self.dualstack)?)
     }
 
-    pub fn listen(&self) {
-        let svr = std::net::UdpSocket::bind(("0.0.0.0", TFTP_PORT)).unwrap();
-        loop {
+    // serve requests arriving on svr, sessions are bound to its address
+    pub fn serve(&self, svr: std::net::UdpSocket) -> Result<(), std::io::Error> {
+        let ip = svr.local_addr()?.ip();
+        let active = Active::default();
+        std::thread::scope(|scope| loop {
             let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
-            let rst = svr.recv_from(&mut raw);
-            if let Err(_) = rst {
-                continue;
-            }
-            let (amt, clt) = rst.unwrap();
-            let pkt = Packet::decode(&raw[0..amt].to_vec(), amt);
-            match pkt {
-                Packet::Rrq(file, mode) => {
-                    if self.0 {
-                        println!("Rrq(I): file({}) mode({})", file, mode);
+            let (amt, clt) = match svr.recv_from(&mut raw) {
+                Ok(rst) => rst,
+                Err(_)  => continue,
+            };
+            let pkt = match PacketRef::try_decode(&raw[0..amt]) {
+                Ok(pkt) => pkt,
+                Err(e)  => {
+                    if self.info {
+                        println!("Err(I): {}", e);
                     }
-                    self.send(file, clt).unwrap_or_default();
-                },
-                Packet::Wrq(file, mode) => {
-                    if self.0 {
-                        println!("Wrq(I): file({}) mode({})", file, mode);
-                    }
-                    self.recv(file, clt).unwrap_or_default();
-                },
-                _ => {
+                    svr.send_to(&Packet::newerr(ErrorCode::IllegalOperation, e).encode(), clt).unwrap_or_default();
                     continue;
                 }
+            };
+            match pkt {
+                PacketRef::Rrq(..) | PacketRef::Wrq(..) => {},
+                // an error is never answered
+                PacketRef::Err(..) => continue,
+                _ => {
+                    svr.send_to(&Packet::newerr(ErrorCode::IllegalOperation, "EOR($): request expected").encode(), clt).unwrap_or_default();
+                    continue;
+                },
             }
-        }
+            let slot = match active.admit(clt, &raw[0..amt], self.sessions) {
+                Admit::Slot(slot) => slot,
+                Admit::Again => continue,
+                Admit::Busy => {
+                    if self.info {
+                        println!("Err(O): busy {}", clt);
+                    }
+                    svr.send_to(&Packet::newerr(ErrorCode::Undefined, "EOR($): server busy").encode(), clt).unwrap_or_default();
+                    continue;
+                },
+            };
+            let pkt = Packet::from(pkt);
+            scope.spawn(move || self.session(ip, pkt, clt, slot).unwrap_or_default());
+        })
     }
 
-    fn send(&self, file: String, clt: std::net::SocketAddr) -> Result<(), std::io::Error> {
-        let svr = std::net::UdpSocket::bind(("0.0.0.0", TFTP_TID0))?;
-        svr.set_write_timeout(Some(std::time::Duration::new(TFTP_TIMEOUT, 0)))?;svr.set_read_timeout(Some(std::time::Duration::new(TFTP_TIMEOUT, 0)))?;
-        let mut clt = clt;
-        // send dat
-        let mut buf;
-        let mut dat = std::fs::read(&file)?;
-        let mut len = dat.len();
-        let mut blk = 1;
-        let mut ack = [0u8;TFTP_SIZE_PACKET_MAX];
-        let mut klb;
-        loop {
-            if len <= 0 {
-                break;
-            }
-            if len <= TFTP_SIZE_DATA_BLOCK {
-                svr.send_to(&Packet::newdat(blk, dat).encode(), clt)?;
-                if self.0 {
-                    println!("Dat(O): blk# = {}", blk);
-                }
-                (_  ,  _) = svr.recv_from(&mut ack)?;
-                klb = u16::from_be_bytes([ack[2], ack[3]]);
-                if self.0 {
-                    println!("Ack(I): blk# = {}", klb);
+    fn session(&self, ip: std::net::IpAddr, pkt: Packet, clt: std::net::SocketAddr, slot: Slot) -> Result<(), TftpError> {
+        let session = self.prepare(pkt, clt).map(Blocking, Blocking);
+        let svr = bind((ip, TFTP_TID0).into(), self.dualstack)?;
+        svr.set_write_timeout(Some(std::time::Duration::new(self.timeout, 0)))?;
+        block_on(self.run(&svr, clt, session, slot))
+    }
+
+    // check a request against handlers, backend and policy, opening or creating its file. this blocks on
+    // the backend, the async server runs it on a thread of its own
+    pub(crate) fn prepare(&self, pkt: Packet, clt: std::net::SocketAddr) -> Session<Box<dyn std::io::Read + Send>, Box<dyn TftpWriter + Send>> {
+        match pkt {
+            Packet::Rrq(file, mode, req) => {
+                if self.info {
+                    println!("Rrq(I): file({}) mode({}) opts({:?})", file, mode, req);
                 }
-                if blk != klb {
-                    svr.send_to(&Packet::newerr(0, "EOR($): blk != klb").encode(), clt)?;
-                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "EOR($): blk != klb"));
+                let mode = match Mode::from_name(&mode) {
+                    Some(mode) => mode,
+                    None => return Session::Deny(ErrorCode::IllegalOperation, format!("EOR($): mode({}) unsupported", mode)),
+                };
+                let (src, size) = match self.source(&file, clt, mode, &req) {
+                    Ok(rst) => rst,
+                    Err((code, msgs)) => return Session::Deny(code, msgs),
+                };
+                let src: Box<dyn std::io::Read + Send> = match mode {
+                    Mode::Octet    => src,
+                    Mode::Netascii => Box::new(Encoder::new(src)),
+                };
+                let (opts, oack) = negotiate_with(&req, OpCode::Rrq, size, self.timeout, self.blksize, self.windowsize);
+                Session::Send(src, opts, oack)
+            },
+            Packet::Wrq(file, mode, req) => {
+                if self.info {
+                    println!("Wrq(I): file({}) mode({}) opts({:?})", file, mode, req);
                 }
-                break;
-            } else {
-                buf = dat.split_off(TFTP_SIZE_DATA_BLOCK);
-                svr.send_to(&Packet::newdat(blk, dat).encode(), clt)?;
-                if self.0 {
-                    println!("Dat(O): blk# = {}", blk);
+                let mode = match Mode::from_name(&mode) {
+                    Some(mode) => mode,
+                    None => return Session::Deny(ErrorCode::IllegalOperation, format!("EOR($): mode({}) unsupported", mode)),
+                };
+                if let Err(e) = self.policy.writable(self.backend.as_ref(), &file) {
+                    return Session::Deny(ErrorCode::from(&e), e.to_string());
                 }
-                (_  ,clt) = svr.recv_from(&mut ack)?;
-                klb = u16::from_be_bytes([ack[2], ack[3]]);
-                if self.0 {
-                    println!("Ack(I): blk# = {}", klb);
+                let (opts, oack) = negotiate_with(&req, OpCode::Wrq, None, self.timeout, self.blksize, self.windowsize);
+                // check quota
+                if opts.tsize.unwrap_or(0) > self.quota.unwrap_or(u64::MAX) {
+                    return Session::Deny(ErrorCode::DiskFull, String::from("EOR($): tsize > quota"));
                 }
-                if blk != klb {
-                    svr.send_to(&Packet::newerr(0, "EOR($): blk != klb").encode(), clt)?;
-                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "EOR($): blk != klb"));
+                match self.backend.create(&file, self.policy.write == WriteMode::NewOnly) {
+                    Ok(dst) if mode == Mode::Netascii => Session::Recv(Box::new(Decoder::new(dst)), opts, oack),
+                    Ok(dst) => Session::Recv(dst, opts, oack),
+                    Err(e) => Session::Deny(ErrorCode::from(&e), e.to_string()),
                 }
-                dat = buf;
+            },
+            _ => Session::Deny(ErrorCode::IllegalOperation, String::from("EOR($): request expected")),
+        }
+    }
 
-                blk = blk + 1;
-                len = len - TFTP_SIZE_DATA_BLOCK;
+    // what to send for a rrq of file, with its size on the wire if known
+    pub(crate) fn source(&self, file: &str, clt: std::net::SocketAddr, mode: Mode, req: &PacketOptions) -> Result<Opened, (ErrorCode, String)> {
+        let (opts, _) = negotiate_with(req, OpCode::Rrq, None, self.timeout, self.blksize, self.windowsize);
+        let mut file = file.to_string();
+        for handler in &self.handlers {
+            match handler(&file, clt, &opts) {
+                Answer::Data(dat) => {
+                    let size = mode.measure(dat.as_slice()).ok();
+                    return Ok((Box::new(std::io::Cursor::new(dat)), size));
+                },
+                // the file redirected to is not handled again
+                Answer::Redirect(to) => {
+                    if self.info {
+                        println!("Rrq(I): file({}) -> file({})", file, to);
+                    }
+                    file = to;
+                    break;
+                },
+                Answer::Deny(code, msgs) => return Err((code, msgs)),
+                Answer::Pass => {},
             }
         }
-        if self.0 {
-            println!("EOR($): size = {}", (blk as usize - 1) * TFTP_SIZE_DATA_BLOCK + len);
+        let (src, size) = self.backend.open(&file).map_err(|e| (ErrorCode::from(&e), e.to_string()))?;
+        let size = match mode {
+            Mode::Netascii if option(req, TFTP_OPTION_TSIZE).is_some() => self.backend.open(&file).and_then(|(src, _)| mode.measure(src)).ok(),
+            Mode::Netascii => None,
+            Mode::Octet    => size,
+        };
+        Ok((src, size))
+    }
+
+    // run a prepared session on svr, a socket of its own. its slot is given back before an upload dallies
+    pub(crate) async fn run<S: Socket, R: Source, W: Sink>(&self, svr: &S, clt: std::net::SocketAddr, session: Session<R, W>, slot: Slot) -> Result<(), TftpError> {
+        match session {
+            Session::Send(mut src, opts, oack) => {
+                let mut transfer = Transfer::new(svr, clt, opts, self.info).retry(self.retries, self.backoff).rollover(self.rollover);
+                // send oack
+                if !oack.is_empty() {
+                    transfer.offer(oack).await?;
+                }
+                // send dat
+                transfer.push(&mut src).await?;
+            },
+            Session::Recv(mut dst, opts, oack) => {
+                let mut transfer = Transfer::new(svr, clt, opts, self.info).retry(self.retries, self.backoff).rollover(self.rollover);
+                if oack.is_empty() {
+                    // send ack
+                    transfer.answer(Packet::newack(0)).await?;
+                } else {
+                    // send oack
+                    transfer.answer(Packet::newoack(oack)).await?;
+                }
+                // recv dat
+                transfer.pull(None, self.quota.unwrap_or(u64::MAX), &mut dst).await?;
+                drop(slot);
+                transfer.dally().await;
+            },
+            Session::Deny(code, msgs) => {
+                svr.send_to(&Packet::newerr(code, msgs).encode(), clt).await?;
+            },
         }
         Ok(())
     }
+}
 
-    fn recv(&self, file: String, clt: std::net::SocketAddr) -> Result<(), std::io::Error>  {
-        let svr = std::net::UdpSocket::bind(("0.0.0.0", TFTP_TID0))?;
-        svr.set_write_timeout(Some(std::time::Duration::new(TFTP_TIMEOUT, 0)))?;svr.set_read_timeout(Some(std::time::Duration::new(TFTP_TIMEOUT, 0)))?;
-        let mut clt = clt;
-        let mut amt;
-        // send ack
-        svr.send_to(&Packet::newack(0).encode(), clt)?;
-        if self.0 {
-            println!("Ack(O): blk# = 0");
-        }
-        // recv dat
-        let mut buf = vec![];
-        let mut dat = [0u8;TFTP_SIZE_PACKET_MAX];
-        let mut blk = 1;
-        let mut klb;
-        loop {
-            (amt, clt) = svr.recv_from(&mut dat)?;
-            klb = u16::from_be_bytes([dat[2], dat[3]]);
-            if self.0 {
-                println!("Dat(I): blk# = {}", klb);
-            }
-            if blk != klb {
-                svr.send_to(&Packet::newerr(0, "EOR($): blk != klb").encode(), clt)?;
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "EOR($): blk != klb"));
-            }
-            svr.send_to(&Packet::newack(blk).encode(), clt)?;
-            if self.0 {
-                println!("Ack(O): blk# = {}", blk);
-            }
-            buf.extend_from_slice(&dat[4..amt]);
-            if amt < TFTP_SIZE_PACKET_MAX {
-                if self.0 {
-                    amt = amt - 4;
-                }
-                break;
-            }
-            blk = blk + 1;
+// a server configured step by step, from the defaults of Server::default
+pub struct ServerBuilder {
+    server: Server,
+}
+
+impl ServerBuilder {
+    // the address to listen on, any ipv4 address by default
+    pub fn bind<I: Into<std::net::IpAddr>>(mut self, ip: I) -> Self {
+        self.server.addr.set_ip(ip.into());
+        self
+    }
+
+    // whether an ipv6 address also serves ipv4 clients, as the system sets it by default
+    pub fn dualstack(mut self, dualstack: bool) -> Self {
+        self.server.dualstack = Some(dualstack);
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.server.addr.set_port(port);
+        self
+    }
+
+    pub fn root<P: AsRef<std::path::Path>>(mut self, root: P) -> Self {
+        self.server.root(root);
+        self
+    }
+
+    // seconds to wait before a retransmission, unless the client negotiates it
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.server.timeout = timeout.clamp(TFTP_TIMEOUT_MIN, TFTP_TIMEOUT_MAX);
+        self
+    }
+
+    pub fn retry(mut self, retries: u32, backoff: u32) -> Self {
+        self.server.retry(retries, backoff);
+        self
+    }
+
+    // the largest blksize granted to clients
+    pub fn blksize(mut self, blksize: usize) -> Self {
+        self.server.blksize = blksize.clamp(TFTP_SIZE_BLOCK_MIN, TFTP_SIZE_BLOCK_MAX);
+        self
+    }
+
+    // the largest windowsize granted to clients
+    pub fn windowsize(mut self, windowsize: u16) -> Self {
+        self.server.windowsize = windowsize.clamp(1, TFTP_WINDOWSIZE_MAX);
+        self
+    }
+
+    pub fn info(mut self, info: bool) -> Self {
+        self.server.info = info;
+        self
+    }
+
+    pub fn quota(mut self, quota: Option<u64>) -> Self {
+        self.server.quota = quota;
+        self
+    }
+
+    pub fn rollover(mut self, rollover: Rollover) -> Self {
+        self.server.rollover(rollover);
+        self
+    }
+
+    pub fn sessions(mut self, sessions: usize) -> Self {
+        self.server.sessions(sessions);
+        self
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.server.policy(policy);
+        self
+    }
+
+    pub fn backend<B: TftpBackend + 'static>(mut self, backend: B) -> Self {
+        self.server.backend(backend);
+        self
+    }
+
+    pub fn handler<F: Fn(&str, std::net::SocketAddr, &Options) -> Answer + Send + Sync + 'static>(mut self, handler: F) -> Self {
+        self.server.handler(handler);
+        self
+    }
+
+    pub fn build(self) -> Server {
+        self.server
+    }
+}
+
+// bind a socket of the family of addr, an ipv6 one accepting ipv4 as well if dualstack
+pub(crate) fn bind(addr: std::net::SocketAddr, dualstack: Option<bool>) -> Result<std::net::UdpSocket, std::io::Error> {
+    let sock = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    if let (true, Some(dualstack)) = (addr.is_ipv6(), dualstack) {
+        sock.set_only_v6(!dualstack)?;
+    }
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+// serve on an ephemeral port of the address the builder binds to, returning that address
+#[cfg(test)]
+pub(crate) fn spawn(builder: ServerBuilder) -> std::net::SocketAddr {
+    let server = builder.port(0).build();
+    let svr = bind(server.addr, server.dualstack).unwrap();
+    let addr = svr.local_addr().unwrap();
+    std::thread::spawn(move || server.serve(svr));
+    addr
+}
+
+#[test]
+fn test_policy() {
+    let root = std::env::temp_dir().join(format!("tftp.policy.{}", std::process::id()));
+    std::fs::create_dir_all(root.join("backups")).unwrap();
+    std::fs::write(root.join("backups/old"), b"").unwrap();
+    let backend = Directory::new(&root);
+    let kind = |policy: &Policy, file: &str| policy.writable(&backend, file).map_err(|e| e.kind());
+
+    assert_eq!(kind(&Policy::default(), "backups/old"), Ok(()));
+    assert_eq!(kind(&Policy::default(), "new"), Ok(()));
+    assert_eq!(kind(&Policy::readonly(), "new"), Err(std::io::ErrorKind::PermissionDenied));
+    let policy = Policy::default().write(WriteMode::NewOnly);
+    assert_eq!(kind(&policy, "backups/old"), Err(std::io::ErrorKind::AlreadyExists));
+    assert_eq!(kind(&policy, "backups/new"), Ok(()));
+    assert_eq!(backend.create("backups/old", true).err().unwrap().kind(), std::io::ErrorKind::AlreadyExists);
+    let policy = Policy::default().allow("backups").unwrap();
+    assert_eq!(kind(&policy, "backups/old"), Ok(()));
+    assert_eq!(kind(&policy, "backups/sw1/new"), Ok(()));
+    assert_eq!(kind(&policy, "new"), Err(std::io::ErrorKind::PermissionDenied));
+    assert_eq!(kind(&policy, "backups.new"), Err(std::io::ErrorKind::PermissionDenied));
+    // links are followed out of or into the directories allowed
+    #[cfg(unix)]
+    {
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::os::unix::fs::symlink("../etc", root.join("backups/link")).unwrap();
+        std::os::unix::fs::symlink("backups", root.join("saved")).unwrap();
+        assert_eq!(kind(&policy, "backups/link/passwd"), Err(std::io::ErrorKind::PermissionDenied));
+        assert_eq!(kind(&policy, "saved/new"), Ok(()));
+        let policy = Policy::default().allow("saved").unwrap();
+        assert_eq!(kind(&policy, "backups/new"), Ok(()));
+        assert_eq!(kind(&policy, "backups/link/passwd"), Err(std::io::ErrorKind::PermissionDenied));
+    }
+    assert_eq!(Policy::default().allow("/etc").err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+    assert_eq!(Policy::default().allow("../etc").err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_server() {
+    let root = std::env::temp_dir().join(format!("tftp.server.{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let dat = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+    std::fs::write(root.join("src"), &dat).unwrap();
+    let addr = spawn(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).root(&root).timeout(1).blksize(1024));
+
+    let mut client = crate::tftp::client::Client::new(addr).unwrap();
+    client.option("blksize", 1428);
+    client.recv("src", root.join("dst")).unwrap();
+    assert_eq!(std::fs::read(root.join("dst")).unwrap(), dat);
+    client.send(root.join("dst"), "sub/dst").unwrap();
+    assert_eq!(std::fs::read(root.join("sub/dst")).unwrap(), dat);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_server_windowsize() {
+    let files = Memory::new();
+    files.insert("src", vec![0u8;3000]).unwrap();
+    let addr = spawn(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).backend(files).windowsize(8));
+
+    let clt = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    clt.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+    let rrq = Packet::newrrq("src", "octet").with_options(vec![(String::from("windowsize"), String::from("1000"))]);
+    clt.send_to(&rrq.encode(), addr).unwrap();
+    let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+    let (amt, tid) = clt.recv_from(&mut raw).unwrap();
+    assert_eq!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::newoack(vec![(String::from("windowsize"), String::from("8"))]));
+    clt.send_to(&Packet::newerr(ErrorCode::Undefined, "done").encode(), tid).unwrap();
+    // no more than TFTP_WINDOWSIZE_MAX is granted, whatever the server is given
+    assert_eq!(Server::builder().windowsize(u16::MAX).build().windowsize, TFTP_WINDOWSIZE_MAX);
+    assert_eq!(Server::builder().windowsize(0).build().windowsize, 1);
+}
+
+#[test]
+fn test_server_v6() {
+    let root = std::env::temp_dir().join(format!("tftp.server6.{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let dat = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+    std::fs::write(root.join("src"), &dat).unwrap();
+    let port = spawn(Server::builder().bind(std::net::Ipv6Addr::LOCALHOST).root(&root)).port();
+    let client = crate::tftp::client::Client::new((std::net::Ipv6Addr::LOCALHOST, port)).unwrap();
+    client.recv("src", root.join("dst6")).unwrap();
+    assert_eq!(std::fs::read(root.join("dst6")).unwrap(), dat);
+    client.send(root.join("dst6"), "sub/dst6").unwrap();
+    assert_eq!(std::fs::read(root.join("sub/dst6")).unwrap(), dat);
+    // ipv4 clients of a dual stack server
+    let port = spawn(Server::builder().bind(std::net::Ipv6Addr::UNSPECIFIED).dualstack(true).root(&root)).port();
+    let client = crate::tftp::client::Client::new((std::net::Ipv4Addr::LOCALHOST, port)).unwrap();
+    client.recv("src", root.join("dst4")).unwrap();
+    assert_eq!(std::fs::read(root.join("dst4")).unwrap(), dat);
+    client.send(root.join("dst4"), "sub/dst4").unwrap();
+    assert_eq!(std::fs::read(root.join("sub/dst4")).unwrap(), dat);
+    let client = crate::tftp::client::Client::new((std::net::Ipv6Addr::LOCALHOST, port)).unwrap();
+    client.recv("src", root.join("dst6")).unwrap();
+    assert_eq!(std::fs::read(root.join("dst6")).unwrap(), dat);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_server_memory() {
+    let files = Memory::new();
+    files.insert("boot/host.ipxe", "#!ipxe\nchain http://boot/${mac}\n").unwrap();
+    let client = crate::tftp::client::Client::new(spawn(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).backend(files.clone()).policy(Policy::default().write(WriteMode::NewOnly)))).unwrap();
+
+    let mut buf = vec![];
+    client.get_to_writer("boot/host.ipxe", &mut buf).unwrap();
+    assert_eq!(buf, files.get("boot/host.ipxe").unwrap());
+    assert!(matches!(client.get_to_writer("none", &mut buf), Err(TftpError::Remote(1, _))));
+    client.put_from_reader("up/cfg", &b"hostname sw1"[..]).unwrap();
+    assert_eq!(files.get("up/cfg").unwrap(), b"hostname sw1");
+    assert!(matches!(client.put_from_reader("up/cfg", &b""[..]), Err(TftpError::Remote(6, _))));
+}
+
+#[test]
+fn test_server_handler() {
+    let files = Memory::new();
+    files.insert("pxelinux.cfg/default", "default local\n").unwrap();
+    files.insert("grub.cfg", "set timeout=5\n").unwrap();
+    let server = Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).backend(files.clone())
+        .handler(|file, clt, opts| match file {
+            _ if file.starts_with("pxelinux.cfg/01-") => Answer::Data(format!("# {} {} {}\n", &file[16..], clt.ip(), opts.blksize).into_bytes()),
+            _ if file.starts_with("grub.cfg-") => Answer::Redirect("grub.cfg".to_string()),
+            "secret" => Answer::Deny(ErrorCode::AccessViolation, "denied".to_string()),
+            _ => Answer::Pass,
+        })
+        .handler(|_, _, _| Answer::Deny(ErrorCode::FileNotFound, "unreachable".to_string()));
+    let client = crate::tftp::client::Client::new(spawn(server)).unwrap();
+
+    let mut buf = vec![];
+    client.get_to_writer("pxelinux.cfg/01-aa-bb-cc-dd-ee-ff", &mut buf).unwrap();
+    assert_eq!(buf, b"# aa-bb-cc-dd-ee-ff 127.0.0.1 512\n");
+    let mut buf = vec![];
+    client.get_to_writer("grub.cfg-127.0.0.1", &mut buf).unwrap();
+    assert_eq!(buf, b"set timeout=5\n");
+    assert!(matches!(client.get_to_writer("secret", &mut buf), Err(TftpError::Remote(2, m)) if m == "denied"));
+    // the first handler passes, the next one denies
+    assert!(matches!(client.get_to_writer("pxelinux.cfg/default", &mut buf), Err(TftpError::Remote(1, _))));
+}
+
+#[test]
+fn test_server_error() {
+    let files = Memory::new();
+    files.insert("src", "dat").unwrap();
+    let addr = spawn(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).backend(files).policy(Policy::readonly()));
+
+    let clt = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    clt.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+    let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+    for (pkt, code) in [
+        (Packet::newrrq("none", "octet"), ErrorCode::FileNotFound),
+        (Packet::newwrq("src", "octet"), ErrorCode::AccessViolation),
+        (Packet::newrrq("src", "mail"), ErrorCode::IllegalOperation),
+        (Packet::newack(1), ErrorCode::IllegalOperation),
+    ] {
+        clt.send_to(&pkt.encode(), addr).unwrap();
+        let (amt, _) = clt.recv_from(&mut raw).unwrap();
+        assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(c, _) if c == u16::from(code)));
+    }
+    clt.send_to(&[0, 9], addr).unwrap();
+    let (amt, _) = clt.recv_from(&mut raw).unwrap();
+    assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(4, _)));
+    // a truncated ack in the middle of a transfer ends it
+    clt.send_to(&Packet::newrrq("src", "octet").encode(), addr).unwrap();
+    let (_, tid) = clt.recv_from(&mut raw).unwrap();
+    clt.send_to(&[0, 4, 0], tid).unwrap();
+    let (amt, _) = clt.recv_from(&mut raw).unwrap();
+    assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(4, m) if m == "EOR($): truncated packet: 3 bytes"));
+}
+
+#[test]
+fn test_server_abort() {
+    let root = std::env::temp_dir().join(format!("tftp.abort.{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("cfg"), b"old").unwrap();
+    let addr = spawn(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).backend(Directory::new(&root).fsync(true)));
+
+    // an upload aborted after its first block
+    let clt = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    clt.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+    let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+    for file in ["cfg", "new"] {
+        clt.send_to(&Packet::newwrq(file, "octet").encode(), addr).unwrap();
+        let (_, tid) = clt.recv_from(&mut raw).unwrap();
+        clt.send_to(&Packet::newdat(1, vec![0;TFTP_SIZE_DATA_BLOCK]).encode(), tid).unwrap();
+        let (amt, _) = clt.recv_from(&mut raw).unwrap();
+        assert_eq!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::newack(1));
+        clt.send_to(&Packet::newerr(ErrorCode::Undefined, "abort").encode(), tid).unwrap();
+    }
+    let left = || std::fs::read_dir(&root).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect::<Vec<_>>();
+    for _ in 0..20 {
+        if left().len() == 1 {
+            break;
         }
-        let file = file.try_create_parent(true)?;
-        std::fs::write(&file, buf)?;
-        if self.0 {
-            println!("EOR($): size = {}", (blk as usize - 1) * TFTP_SIZE_DATA_BLOCK + amt);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(left(), vec!["cfg"]);
+    assert_eq!(std::fs::read(root.join("cfg")).unwrap(), b"old");
+    // a complete one replaces the file
+    let client = crate::tftp::client::Client::new(addr).unwrap();
+    client.put_from_reader("cfg", &b"new"[..]).unwrap();
+    assert_eq!(std::fs::read(root.join("cfg")).unwrap(), b"new");
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_server_sessions() {
+    let files = Memory::new();
+    files.insert("a", vec![0xaa;700]).unwrap();
+    files.insert("b", vec![0xbb;300]).unwrap();
+    let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+
+    // two requests of one client overlap, each served from a tid of its own
+    let addr = spawn(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).backend(files.clone()));
+    let clt = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    clt.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+    clt.send_to(&Packet::newrrq("a", "octet").encode(), addr).unwrap();
+    clt.send_to(&Packet::newrrq("b", "octet").encode(), addr).unwrap();
+    let mut got = std::collections::HashMap::<std::net::SocketAddr, Vec<u8>>::new();
+    let mut end = 0;
+    while end < 2 {
+        let (amt, tid) = clt.recv_from(&mut raw).unwrap();
+        let Packet::Dat(blk, dat) = Packet::try_decode(&raw[..amt]).unwrap() else {
+            panic!("dat expected");
+        };
+        if dat.len() < TFTP_SIZE_DATA_BLOCK {
+            end += 1;
         }
-        Ok(())
+        got.entry(tid).or_default().extend(dat);
+        clt.send_to(&Packet::newack(blk).encode(), tid).unwrap();
     }
+    let mut got = got.into_values().collect::<Vec<_>>();
+    got.sort();
+    assert_eq!(got, vec![vec![0xaa;700], vec![0xbb;300]]);
+
+    // a single session, another client is told the server is busy until it ends
+    let addr = spawn(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).backend(files.clone()).sessions(1));
+    clt.send_to(&Packet::newrrq("a", "octet").encode(), addr).unwrap();
+    let (_, tid) = clt.recv_from(&mut raw).unwrap();
+    let other = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    other.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+    other.send_to(&Packet::newrrq("b", "octet").encode(), addr).unwrap();
+    let (amt, _) = other.recv_from(&mut raw).unwrap();
+    assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(0, m) if m == "EOR($): server busy"));
+    clt.send_to(&Packet::newerr(ErrorCode::Undefined, "done").encode(), tid).unwrap();
+    let dat = (0..20).find_map(|_| {
+        other.send_to(&Packet::newrrq("b", "octet").encode(), addr).unwrap();
+        let (amt, _) = other.recv_from(&mut raw).unwrap();
+        match Packet::try_decode(&raw[..amt]).unwrap() {
+            Packet::Dat(1, dat) => Some(dat),
+            _ => {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                None
+            },
+        }
+    });
+    assert_eq!(dat, Some(vec![0xbb;300]));
+
+    // an upload gives its session back once complete, rather than after dallying
+    let addr = spawn(Server::builder().bind(std::net::Ipv4Addr::LOCALHOST).backend(files).sessions(1));
+    let client = crate::tftp::client::Client::new(addr).unwrap();
+    client.put_from_reader("c", &b"up"[..]).unwrap();
+    let dat = (0..20).find_map(|_| {
+        let mut buf = vec![];
+        match client.get_to_writer("c", &mut buf) {
+            Ok(_) => Some(buf),
+            Err(_) => {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                None
+            },
+        }
+    });
+    assert_eq!(dat, Some(b"up".to_vec()));
 }
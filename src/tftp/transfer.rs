@@ -0,0 +1,693 @@
+/*++ @file
+
+    Copyright ©2024-2024 Liu Yi, efikarl@yeah.net
+
+    This program is just made available under the terms and conditions of the
+    MIT license: http://www.efikarl.com/mit-license.html
+
+    THE PROGRAM IS DISTRIBUTED UNDER THE MIT LICENSE ON AN "AS IS" BASIS,
+    WITHOUT WARRANTIES OR REPRESENTATIONS OF ANY KIND, EITHER EXPRESS OR IMPLIED.
+--*/
+
+use crate::tftp::packet::*;
+use crate::tftp::option::*;
+use crate::tftp::error::*;
+use crate::tftp::backend::*;
+
+pub const TFTP_RETRIES          :   u32 =                        5;
+pub const TFTP_BACKOFF          :   u32 =                        1;
+
+// time to wait for a packet, cut to what is left until the deadline
+pub(crate) fn remain(interval: std::time::Duration, deadline: Option<std::time::Instant>) -> Result<std::time::Duration, TftpError> {
+    match deadline.map(|deadline| deadline.saturating_duration_since(std::time::Instant::now())) {
+        Some(left) if left.is_zero() => Err(TftpError::Timeout),
+        Some(left) => Ok(interval.min(left)),
+        None => Ok(interval),
+    }
+}
+
+// fail a wait that timed out at the deadline, rather than retransmitting
+pub(crate) fn expire(deadline: Option<std::time::Instant>) -> Result<(), TftpError> {
+    match deadline {
+        Some(deadline) if std::time::Instant::now() >= deadline => Err(TftpError::Timeout),
+        _ => Ok(()),
+    }
+}
+
+// block number following 65535, files of more than 65535 blocks need the peers to agree on it
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Rollover {
+    #[default]
+    Zero,
+    One,
+}
+
+impl Rollover {
+    // block number of the nth block, the 0th one being the request itself
+    pub fn block(&self, n: usize) -> u16 {
+        match self {
+            Rollover::Zero => (n % 0x10000) as u16,
+            Rollover::One  => if n == 0 { 0 } else { ((n - 1) % 0xffff + 1) as u16 },
+        }
+    }
+}
+
+// the datagram socket of a transfer. the engine is written once as async code over it: a tokio socket
+// is awaited, while a blocking one completes every call in place, its futures being ready at once
+pub trait Socket: Sync {
+    fn send_to(&self, buf: &[u8], peer: std::net::SocketAddr) -> impl std::future::Future<Output = Result<usize, std::io::Error>> + Send;
+    // recv a datagram and its sender, none if nothing arrived until then
+    fn recv_until(&self, buf: &mut [u8], until: std::time::Instant) -> impl std::future::Future<Output = Result<Option<(usize, std::net::SocketAddr)>, std::io::Error>> + Send;
+}
+
+impl Socket for std::net::UdpSocket {
+    fn send_to(&self, buf: &[u8], peer: std::net::SocketAddr) -> impl std::future::Future<Output = Result<usize, std::io::Error>> + Send {
+        std::future::ready(std::net::UdpSocket::send_to(self, buf, peer))
+    }
+
+    fn recv_until(&self, buf: &mut [u8], until: std::time::Instant) -> impl std::future::Future<Output = Result<Option<(usize, std::net::SocketAddr)>, std::io::Error>> + Send {
+        let left = until.saturating_duration_since(std::time::Instant::now());
+        let rst = match left.is_zero() {
+            true  => Ok(None),
+            false => self.set_read_timeout(Some(left)).and_then(|_| match self.recv_from(buf) {
+                Ok(rst) => Ok(Some(rst)),
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => Ok(None),
+                Err(e) => Err(e),
+            }),
+        };
+        std::future::ready(rst)
+    }
+}
+
+// run a transfer over blocking sockets and files, which never leave a future pending
+pub(crate) fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    match fut.as_mut().poll(&mut std::task::Context::from_waker(std::task::Waker::noop())) {
+        std::task::Poll::Ready(out) => out,
+        std::task::Poll::Pending => unreachable!("EOR($): blocking i/o pending"),
+    }
+}
+
+// where the blocks sent come from
+pub(crate) trait Source {
+    // read a whole block into blk, a short one only at the end
+    fn fill(&mut self, blk: &mut [u8]) -> impl std::future::Future<Output = Result<usize, std::io::Error>> + Send;
+}
+
+// where the blocks received go
+pub(crate) trait Sink {
+    fn write_all(&mut self, buf: &[u8]) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send;
+    // the end of the file, reached before the last block is acknowledged
+    fn finish(&mut self) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send;
+}
+
+// a blocking reader or writer, read and written in place, ready once called
+pub(crate) struct Blocking<T>(pub T);
+
+impl<R: std::io::Read> Source for Blocking<R> {
+    fn fill(&mut self, blk: &mut [u8]) -> impl std::future::Future<Output = Result<usize, std::io::Error>> + Send {
+        std::future::ready(fill(&mut self.0, blk))
+    }
+}
+
+impl<W: TftpWriter> Sink for Blocking<W> {
+    fn write_all(&mut self, buf: &[u8]) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send {
+        std::future::ready(self.0.write_all(buf))
+    }
+
+    fn finish(&mut self) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send {
+        std::future::ready(self.0.commit())
+    }
+}
+
+// a plain writer of the caller, committed by a flush
+pub(crate) struct Flushed<W>(pub W);
+
+impl<W: std::io::Write> std::io::Write for Flushed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: std::io::Write> TftpWriter for Flushed<W> {
+    fn commit(&mut self) -> Result<(), std::io::Error> {
+        self.0.flush()
+    }
+}
+
+// read a whole block from src, a short one only at the end of it
+pub(crate) fn fill<R: std::io::Read + ?Sized>(src: &mut R, blk: &mut [u8]) -> Result<usize, std::io::Error> {
+    let mut len = 0;
+    while len < blk.len() {
+        match src.read(&mut blk[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+// the data phase of a transfer, shared by server and client once the request has been acknowledged.
+// lost packets are retransmitted after timeout, retries times at most with the timeout multiplied by
+// backoff each time, and all of it fails after the deadline if any. duplicate acks never trigger a
+// retransmission (sorcerer's apprentice syndrome)
+pub struct Transfer<'a, S = std::net::UdpSocket> {
+    sock    : &'a S,
+    pub peer: std::net::SocketAddr,
+    opts    : Options,
+    info    : bool,
+    retries : u32,
+    backoff : u32,
+    rollover: Rollover,
+    tries   : u32,
+    interval: std::time::Duration,
+    deadline: Option<std::time::Instant>,
+    report  : Option<&'a (dyn Fn(u64) + Sync)>,
+    last    : Vec<u8>,
+}
+
+impl<'a, S: Socket> Transfer<'a, S> {
+    pub fn new(sock: &'a S, peer: std::net::SocketAddr, opts: Options, info: bool) -> Self {
+        let interval = std::time::Duration::from_secs(opts.timeout);
+        Transfer { sock, peer, opts, info, retries: TFTP_RETRIES, backoff: TFTP_BACKOFF, rollover: Rollover::default(), tries: 0, interval, deadline: None, report: None, last: vec![] }
+    }
+
+    pub fn retry(mut self, retries: u32, backoff: u32) -> Self {
+        self.retries = retries;
+        self.backoff = std::cmp::max(backoff, 1);
+        self
+    }
+
+    pub fn rollover(mut self, rollover: Rollover) -> Self {
+        self.rollover = rollover;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Option<std::time::Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    // told the bytes done so far: acknowledged when sending, written when receiving
+    pub fn report(mut self, report: &'a (dyn Fn(u64) + Sync)) -> Self {
+        self.report = Some(report);
+        self
+    }
+
+    fn tell(&self, len: u64) {
+        if let Some(report) = self.report {
+            report(len);
+        }
+    }
+
+    // tell the peer why the transfer ends here
+    async fn error(&self, code: ErrorCode, msgs: &str) {
+        self.sock.send_to(&Packet::newerr(code, msgs).encode(), self.peer).await.unwrap_or_default();
+    }
+
+    // the peer sent what it must not send, which it is told
+    async fn violate(&self, msgs: &str) -> TftpError {
+        self.error(ErrorCode::IllegalOperation, msgs).await;
+        TftpError::Protocol(msgs.to_string())
+    }
+
+    fn abort(code: u16, msgs: &[u8]) -> TftpError {
+        TftpError::Remote(code, String::from_utf8_lossy(msgs).into_owned())
+    }
+
+    // recv a packet of the peer, none if nothing arrived in time. packets of any other tid are answered
+    // with error 5 and dropped, without extending the wait, while one of the peer not decoded ends the
+    // transfer with error 4
+    async fn wait<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<PacketRef<'b>>, TftpError> {
+        let until = std::time::Instant::now() + remain(self.interval, self.deadline)?;
+        loop {
+            match self.sock.recv_until(buf, until).await? {
+                Some((_, peer)) if peer != self.peer => {
+                    if self.info {
+                        println!("Tid(I): {} != {}", peer, self.peer);
+                    }
+                    self.sock.send_to(&Packet::newerr(ErrorCode::UnknownTid, "EOR($): unknown tid").encode(), peer).await.unwrap_or_default();
+                },
+                Some((amt, _)) => match PacketRef::try_decode(&buf[..amt]) {
+                    Ok(pkt) => return Ok(Some(pkt)),
+                    Err(e) => return Err(self.violate(&format!("EOR($): {}", e)).await),
+                },
+                None => return expire(self.deadline).map(|_| None),
+            }
+        }
+    }
+
+    // account a timeout, failing when out of retries
+    fn timeout(&mut self) -> Result<(), TftpError> {
+        if self.tries >= self.retries {
+            return Err(TftpError::Timeout);
+        }
+        self.tries += 1;
+        self.interval *= self.backoff;
+        if self.info {
+            println!("Tmo(I): try# = {}", self.tries);
+        }
+        Ok(())
+    }
+
+    fn progress(&mut self) {
+        self.tries = 0;
+        self.interval = std::time::Duration::from_secs(self.opts.timeout);
+    }
+
+    // send a reply to the request (ack or oack), retransmitted by pull on timeout
+    pub(crate) async fn answer(&mut self, pkt: Packet) -> Result<(), TftpError> {
+        self.last = pkt.encode();
+        self.sock.send_to(&self.last, self.peer).await?;
+        if self.info {
+            match pkt {
+                Packet::Ack(blk) => println!("Ack(O): blk# = {}", blk),
+                _ => println!("{:?}(O)", pkt.opcode()),
+            }
+        }
+        Ok(())
+    }
+
+    async fn ack(&mut self, blk: u16) -> Result<(), TftpError> {
+        if self.info {
+            println!("Ack(O): blk# = {}", blk);
+        }
+        self.last.resize(4, 0);
+        PacketRef::Ack(blk).encode_into(&mut self.last);
+        self.sock.send_to(&self.last, self.peer).await?;
+        Ok(())
+    }
+
+    // send an oack to a rrq and wait for its ack
+    pub(crate) async fn offer(&mut self, oack: PacketOptions) -> Result<(), TftpError> {
+        let pkt = Packet::newoack(oack).encode();
+        let mut ack = [0u8;TFTP_SIZE_PACKET_MAX];
+        loop {
+            self.sock.send_to(&pkt, self.peer).await?;
+            if self.info {
+                println!("Oack(O)");
+            }
+            match self.wait(&mut ack).await? {
+                Some(PacketRef::Ack(0)) => {
+                    if self.info {
+                        println!("Ack(I): blk# = 0");
+                    }
+                    self.progress();
+                    return Ok(());
+                },
+                Some(PacketRef::Err(code, msgs)) => return Err(Self::abort(code, msgs)),
+                Some(_) => return Err(self.violate("EOR($): ack != 0").await),
+                None => self.timeout()?,
+            }
+        }
+    }
+
+    // linger after the last ack of pull, acknowledging again the dat the peer retransmits as it lost that
+    // ack. the peer retransmits as its own interval runs out, so half an interval more is waited for it
+    pub(crate) async fn dally(&mut self) {
+        let Ok(left) = remain(self.interval + self.interval / 2, self.deadline) else {
+            return;
+        };
+        let until = std::time::Instant::now() + left;
+        let mut raw = vec![0u8;self.opts.blksize + 5];
+        while let Ok(Some((amt, peer))) = self.sock.recv_until(&mut raw, until).await {
+            if peer == self.peer && matches!(PacketRef::try_decode(&raw[..amt]), Ok(PacketRef::Dat(..))) {
+                if let (true, Ok(PacketRef::Ack(blk))) = (self.info, PacketRef::try_decode(&self.last)) {
+                    println!("Ack(O): blk# = {}", blk);
+                }
+                self.sock.send_to(&self.last, self.peer).await.unwrap_or_default();
+            }
+        }
+    }
+
+    // send src from block 1, windowsize blocks at a time, rewinding to the block after a short ack.
+    // only the blocks of the current window are held in memory
+    pub(crate) async fn push<R: Source>(&mut self, src: &mut R) -> Result<u64, TftpError> {
+        let mut win = std::collections::VecDeque::<Vec<u8>>::new();
+        let mut ack = [0u8;TFTP_SIZE_PACKET_MAX];
+        let mut out = vec![0u8;self.opts.blksize + 4];
+        let mut len = 0;
+        let mut done = 0;
+        let mut bas = 0;
+        let mut eof = false;
+        let mut snd = true;
+        loop {
+            while win.len() < self.opts.windowsize as usize && !eof {
+                let mut blk = vec![0u8;self.opts.blksize];
+                match src.fill(&mut blk).await {
+                    Ok(amt) => blk.truncate(amt),
+                    Err(e) => {
+                        self.error(ErrorCode::from(&e), "EOR($): read").await;
+                        return Err(e.into());
+                    },
+                }
+                eof = blk.len() < self.opts.blksize;
+                len += blk.len() as u64;
+                win.push_back(blk);
+            }
+            if win.is_empty() {
+                break;
+            }
+            if snd {
+                for (i, blk) in win.iter().enumerate() {
+                    let amt = PacketRef::Dat(self.rollover.block(bas + i + 1), blk).encode_into(&mut out);
+                    self.sock.send_to(&out[..amt], self.peer).await?;
+                    if self.info {
+                        println!("Dat(O): blk# = {}", self.rollover.block(bas + i + 1));
+                    }
+                }
+                snd = false;
+            }
+            match self.wait(&mut ack).await? {
+                Some(PacketRef::Ack(klb)) => {
+                    if self.info {
+                        println!("Ack(I): blk# = {}", klb);
+                    }
+                    // the ack may be for any block of the window, or for the one before it. the latter
+                    // is a duplicate in lock-step, but asks for the window again once pipelined
+                    match (0..win.len()).find(|&i| self.rollover.block(bas + i + 1) == klb) {
+                        Some(i) => {
+                            done += win.drain(..=i).map(|blk| blk.len() as u64).sum::<u64>();
+                            self.tell(done);
+                            bas += i + 1;
+                            snd = true;
+                            self.progress();
+                        },
+                        None if self.rollover.block(bas) == klb && self.opts.windowsize > 1 => snd = true,
+                        None => {},
+                    }
+                },
+                Some(PacketRef::Err(code, msgs)) => return Err(Self::abort(code, msgs)),
+                Some(_) => return Err(self.violate("EOR($): ack expected").await),
+                None => {
+                    self.timeout()?;
+                    snd = true;
+                },
+            }
+        }
+        if self.info {
+            println!("EOR($): size = {}", len);
+        }
+        Ok(len)
+    }
+
+    // recv into dst from block 1, acknowledging the last in-order block of each window. a dat already
+    // received, as the reply to a rrq, is passed in pending, and no more than quota bytes are taken.
+    // duplicate or out-of-order dat is acknowledged once with the last in-order block, and dropped.
+    // it returns once the last block is acknowledged, dally covering the loss of that ack
+    pub(crate) async fn pull<W: Sink>(&mut self, pending: Option<&[u8]>, quota: u64, dst: &mut W) -> Result<u64, TftpError> {
+        let mut len = 0;
+        // a byte more than a dat takes, as the socket would cut a longer one short without telling
+        let mut dat = vec![0u8;self.opts.blksize + 5];
+        let mut pending = pending;
+        let mut blk = 1;
+        let mut cnt = 0;
+        let mut gap = false;
+        loop {
+            let pkt = match pending.take() {
+                Some(raw) => Some(PacketRef::try_decode(raw)?),
+                None => self.wait(&mut dat).await?,
+            };
+            match pkt {
+                Some(PacketRef::Dat(klb, data)) => {
+                    if self.info {
+                        println!("Dat(I): blk# = {}", klb);
+                    }
+                    if data.len() > self.opts.blksize {
+                        return Err(self.violate("EOR($): dat > blksize").await);
+                    }
+                    if klb != self.rollover.block(blk) {
+                        if !gap {
+                            self.ack(self.rollover.block(blk - 1)).await?;
+                            gap = true;
+                            cnt = 0;
+                        }
+                        continue;
+                    }
+                    self.progress();
+                    gap = false;
+                    cnt += 1;
+                    len += data.len() as u64;
+                    if len > quota {
+                        self.error(ErrorCode::DiskFull, "EOR($): size > quota").await;
+                        return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "EOR($): size > quota").into());
+                    }
+                    if let Err(e) = dst.write_all(data).await {
+                        self.error(ErrorCode::from(&e), "EOR($): write").await;
+                        return Err(e.into());
+                    }
+                    self.tell(len);
+                    let eof = data.len() < self.opts.blksize;
+                    // the last block is acknowledged only once everything has been written
+                    if eof {
+                        if let Err(e) = dst.finish().await {
+                            self.error(ErrorCode::from(&e), "EOR($): write").await;
+                            return Err(e.into());
+                        }
+                    }
+                    if eof || cnt == self.opts.windowsize {
+                        self.ack(self.rollover.block(blk)).await?;
+                        cnt = 0;
+                    }
+                    if eof {
+                        break;
+                    }
+                    blk += 1;
+                },
+                Some(PacketRef::Err(code, msgs)) => return Err(Self::abort(code, msgs)),
+                Some(_) => return Err(self.violate("EOR($): dat expected").await),
+                None => {
+                    self.timeout()?;
+                    if cnt > 0 {
+                        self.ack(self.rollover.block(blk - 1)).await?;
+                    } else if !self.last.is_empty() {
+                        self.sock.send_to(&self.last, self.peer).await?;
+                    }
+                    gap = false;
+                    cnt = 0;
+                },
+            }
+        }
+        if self.info {
+            println!("EOR($): size = {}", len);
+        }
+        Ok(len)
+    }
+}
+
+// a transfer over a blocking socket, files being read and written in place
+impl Transfer<'_> {
+    // send a reply to the request (ack or oack), retransmitted by recv on timeout
+    pub fn reply(&mut self, pkt: Packet) -> Result<(), TftpError> {
+        block_on(self.answer(pkt))
+    }
+
+    // send an oack to a rrq and wait for its ack
+    pub fn oack(&mut self, oack: PacketOptions) -> Result<(), TftpError> {
+        block_on(self.offer(oack))
+    }
+
+    pub fn send(&mut self, src: &mut dyn std::io::Read) -> Result<u64, TftpError> {
+        block_on(self.push(&mut Blocking(src)))
+    }
+
+    pub fn recv(&mut self, pending: Option<&[u8]>, quota: u64, dst: &mut dyn std::io::Write) -> Result<u64, TftpError> {
+        block_on(self.pull(pending, quota, &mut Blocking(Flushed(dst))))
+    }
+
+    // linger after recv, acknowledging again the last dat should the peer retransmit it
+    pub fn linger(&mut self) {
+        block_on(self.dally())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// forward datagrams between a and b through a local socket, dropping and duplicating the nth ones
+#[cfg(test)]
+fn shim(a: std::net::SocketAddr, b: std::net::SocketAddr, drop: &'static [usize], dup: &'static [usize]) -> (std::net::SocketAddr, std::thread::JoinHandle<usize>) {
+    let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+    let addr = sock.local_addr().unwrap();
+    let join = std::thread::spawn(move || {
+        let mut raw = [0u8;TFTP_SIZE_BLOCK_MAX + 4];
+        let mut n = 0;
+        while let Ok((amt, from)) = sock.recv_from(&mut raw) {
+            n += 1;
+            let to = if from == a { b } else { a };
+            if drop.contains(&n) {
+                continue;
+            }
+            sock.send_to(&raw[..amt], to).unwrap();
+            if dup.contains(&n) {
+                sock.send_to(&raw[..amt], to).unwrap();
+            }
+        }
+        n
+    });
+    (addr, join)
+}
+
+#[cfg(test)]
+fn lossy(opts: Options, len: usize, drop: &'static [usize], dup: &'static [usize]) -> std::thread::JoinHandle<usize> {
+    let dat = (0..len).map(|i| i as u8).collect::<Vec<u8>>();
+    let src = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dst = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (via, join) = shim(src.local_addr().unwrap(), dst.local_addr().unwrap(), drop, dup);
+    let recv = std::thread::spawn(move || {
+        let mut buf = vec![];
+        let mut transfer = Transfer::new(&dst, via, opts, false);
+        transfer.recv(None, u64::MAX, &mut buf).unwrap();
+        transfer.linger();
+        buf
+    });
+    assert_eq!(Transfer::new(&src, via, opts, false).send(&mut dat.as_slice()).unwrap(), dat.len() as u64);
+    assert_eq!(recv.join().unwrap(), dat);
+    join
+}
+
+#[test]
+fn test_window() {
+    let opts = Options { blksize: 8, windowsize: 4, ..Options::default() };
+    let dat = (0..100u8).collect::<Vec<u8>>();
+    let src = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dst = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let src_sa = src.local_addr().unwrap();
+    let dst_sa = dst.local_addr().unwrap();
+    let recv = std::thread::spawn(move || {
+        let mut buf = vec![];
+        Transfer::new(&dst, src_sa, opts, false).recv(None, u64::MAX, &mut buf).unwrap();
+        buf
+    });
+    assert_eq!(Transfer::new(&src, dst_sa, opts, false).send(&mut dat.as_slice()).unwrap(), dat.len() as u64);
+    assert_eq!(recv.join().unwrap(), dat);
+}
+
+#[test]
+fn test_retransmit() {
+    let opts = Options { blksize: 8, timeout: 1, ..Options::default() };
+    // dat#2 and ack#3 lost
+    lossy(opts, 40, &[3, 6], &[]);
+    let opts = Options { blksize: 8, timeout: 1, windowsize: 4, ..Options::default() };
+    // dat#2 lost in the first window, dat#5 in the second one
+    lossy(opts, 60, &[2, 9], &[]);
+    let opts = Options { blksize: 8, timeout: 1, ..Options::default() };
+    // the last ack lost, the dat retransmitted is acknowledged again while dallying
+    assert_eq!(lossy(opts, 20, &[6], &[]).join().unwrap(), 8);
+}
+
+#[test]
+fn test_duplicate() {
+    let opts = Options { blksize: 8, timeout: 1, ..Options::default() };
+    // 12 packets plus at most a re-ack for each duplicated dat, duplicated acks are dropped without any
+    // retransmission. re-acks race the next dat to the shim, so which packets get duplicated varies
+    assert!(lossy(opts, 40, &[], &[1, 2, 3, 4, 7, 8]).join().unwrap() <= 15);
+    let opts = Options { blksize: 8, timeout: 1, windowsize: 4, ..Options::default() };
+    lossy(opts, 60, &[], &[1, 5, 6]);
+}
+
+#[test]
+fn test_rollover() {
+    assert_eq!([0, 1, 65535, 65536, 65537].map(|n| Rollover::Zero.block(n)), [0, 1, 65535, 0, 1]);
+    assert_eq!([0, 1, 65535, 65536, 65537].map(|n| Rollover::One.block(n)), [0, 1, 65535, 1, 2]);
+    let opts = Options { blksize: 8, windowsize: 64, ..Options::default() };
+    let dat = (0..0x10000 * 8 + 100).map(|i| i as u8).collect::<Vec<u8>>();
+    for rollover in [Rollover::Zero, Rollover::One] {
+        let src = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dst = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let src_sa = src.local_addr().unwrap();
+        let dst_sa = dst.local_addr().unwrap();
+        let recv = std::thread::spawn(move || {
+            let mut buf = vec![];
+            Transfer::new(&dst, src_sa, opts, false).rollover(rollover).recv(None, u64::MAX, &mut buf).unwrap();
+            buf
+        });
+        assert_eq!(Transfer::new(&src, dst_sa, opts, false).rollover(rollover).send(&mut dat.as_slice()).unwrap(), dat.len() as u64);
+        assert_eq!(recv.join().unwrap(), dat);
+    }
+}
+
+#[test]
+fn test_oversize() {
+    let opts = Options { blksize: 8, ..Options::default() };
+    let src = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dst = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let src_sa = src.local_addr().unwrap();
+    // a dat longer than blksize ends the transfer, rather than being taken as a full block
+    src.send_to(&Packet::newdat(1, vec![0;9]).encode(), dst.local_addr().unwrap()).unwrap();
+    let mut buf = vec![];
+    let e = Transfer::new(&dst, src_sa, opts, false).recv(None, u64::MAX, &mut buf).unwrap_err();
+    assert!(matches!(e, TftpError::Protocol(m) if m == "EOR($): dat > blksize"));
+    assert!(buf.is_empty());
+    let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+    let (amt, _) = src.recv_from(&mut raw).unwrap();
+    assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(4, _)));
+    // and so does one passed in pending
+    let dat = Packet::newdat(1, vec![0;TFTP_SIZE_DATA_BLOCK + 1]).encode();
+    assert!(Transfer::new(&dst, src_sa, Options::default(), false).recv(Some(&dat), u64::MAX, &mut buf).is_err());
+    let (amt, _) = src.recv_from(&mut raw).unwrap();
+    assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(4, _)));
+}
+
+#[test]
+fn test_tid() {
+    let opts = Options { blksize: 8, windowsize: 2, ..Options::default() };
+    let dat = (0..100u8).collect::<Vec<u8>>();
+    let src = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dst = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let tid = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let src_sa = src.local_addr().unwrap();
+    let dst_sa = dst.local_addr().unwrap();
+    // queued ahead of the transfer, an ack of the whole file and dat of a fake block 1
+    tid.send_to(&Packet::newack(13).encode(), src_sa).unwrap();
+    tid.send_to(&Packet::newdat(1, vec![0xff;3]).encode(), dst_sa).unwrap();
+    let recv = std::thread::spawn(move || {
+        let mut buf = vec![];
+        Transfer::new(&dst, src_sa, opts, false).recv(None, u64::MAX, &mut buf).unwrap();
+        buf
+    });
+    assert_eq!(Transfer::new(&src, dst_sa, opts, false).send(&mut dat.as_slice()).unwrap(), dat.len() as u64);
+    assert_eq!(recv.join().unwrap(), dat);
+    let mut raw = [0u8;TFTP_SIZE_PACKET_MAX];
+    tid.set_read_timeout(Some(std::time::Duration::from_secs(1))).unwrap();
+    for _ in 0..2 {
+        let (amt, from) = tid.recv_from(&mut raw).unwrap();
+        assert!([src_sa, dst_sa].contains(&from));
+        assert!(matches!(Packet::try_decode(&raw[..amt]).unwrap(), Packet::Err(5, _)));
+    }
+}
+
+#[test]
+fn test_finish() {
+    // a sink taking every block, but failing to make them last
+    struct Full(Vec<u8>);
+    impl std::io::Write for Full {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> Result<(), std::io::Error> {
+            Err(std::io::Error::from(std::io::ErrorKind::StorageFull))
+        }
+    }
+    let opts = Options { blksize: 8, timeout: 1, ..Options::default() };
+    let dat = (0..20u8).collect::<Vec<u8>>();
+    let src = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dst = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let src_sa = src.local_addr().unwrap();
+    let dst_sa = dst.local_addr().unwrap();
+    let recv = std::thread::spawn(move || {
+        let mut buf = Full(vec![]);
+        assert!(Transfer::new(&dst, src_sa, opts, false).recv(None, u64::MAX, &mut buf).is_err());
+        buf.0
+    });
+    // the last block is answered with an error rather than acknowledged
+    let e = Transfer::new(&src, dst_sa, opts, false).send(&mut dat.as_slice()).unwrap_err();
+    assert!(matches!(e, TftpError::Remote(3, _)));
+    assert_eq!(recv.join().unwrap(), dat);
+}